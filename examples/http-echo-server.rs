@@ -62,9 +62,13 @@ fn main() {
             .get_matches();
 
     let bind_addr = matches.value_of("BIND").unwrap().to_owned();
+    let threads = matches.value_of("THREADS").unwrap_or("1").parse().unwrap();
 
-    let server = Server::http(&bind_addr[..]).unwrap();
-    server.listen(echo).unwrap();
+    // One listener per thread, the kernel spreads the connections among them
+    let server = Server::http_reuseport(&bind_addr[..], threads).unwrap();
+    Scheduler::spawn(move|| {
+        server.listen(echo).unwrap();
+    });
 
-    Scheduler::run(matches.value_of("THREADS").unwrap_or("1").parse().unwrap());
+    Scheduler::run(threads);
 }
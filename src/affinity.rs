@@ -0,0 +1,161 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! CPU affinity of the worker threads
+
+use std::io;
+
+/// Number of the CPU cores which are online
+pub fn cpus() -> usize {
+    let n = unsafe { imp::sysconf(imp::_SC_NPROCESSORS_ONLN) };
+    if n < 1 { 1 } else { n as usize }
+}
+
+/// Pin the current thread to the CPU core `cpu`
+pub fn set_cpu(cpu: usize) -> io::Result<()> {
+    imp::set_cpu(cpu)
+}
+
+/// CPU affinity mask of a thread
+#[derive(Clone, PartialEq, Debug)]
+pub struct Mask(imp::Mask);
+
+/// Get the CPU affinity mask of the current thread
+pub fn mask() -> io::Result<Mask> {
+    imp::mask().map(Mask)
+}
+
+/// Set the CPU affinity mask of the current thread, as saved by `mask`
+pub fn set_mask(mask: &Mask) -> io::Result<()> {
+    imp::set_mask(&mask.0)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod imp {
+    use std::io;
+    use std::mem;
+
+    use libc::{c_int, c_long, size_t};
+
+    pub const _SC_NPROCESSORS_ONLN: c_int = 84;
+
+    // `cpu_set_t` of glibc, 1024 bits
+    #[repr(C)]
+    #[derive(Clone, PartialEq, Debug)]
+    pub struct cpu_set_t {
+        bits: [u64; 16],
+    }
+
+    pub type Mask = cpu_set_t;
+
+    extern {
+        pub fn sysconf(name: c_int) -> c_long;
+        fn sched_getaffinity(pid: c_int, cpusetsize: size_t, mask: *mut cpu_set_t) -> c_int;
+        fn sched_setaffinity(pid: c_int, cpusetsize: size_t, mask: *const cpu_set_t) -> c_int;
+    }
+
+    pub fn set_cpu(cpu: usize) -> io::Result<()> {
+        if cpu >= 1024 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "CPU index out of range"));
+        }
+
+        let mut set = cpu_set_t { bits: [0; 16] };
+        set.bits[cpu / 64] |= 1 << (cpu % 64);
+        set_mask(&set)
+    }
+
+    pub fn mask() -> io::Result<Mask> {
+        let mut set = cpu_set_t { bits: [0; 16] };
+
+        // Pid 0 is the calling thread
+        let ret = unsafe { sched_getaffinity(0, mem::size_of::<cpu_set_t>() as size_t, &mut set) };
+        if ret == 0 {
+            Ok(set)
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    pub fn set_mask(set: &Mask) -> io::Result<()> {
+        let ret = unsafe { sched_setaffinity(0, mem::size_of::<cpu_set_t>() as size_t, set) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod imp {
+    use std::io;
+
+    use libc::{c_int, c_long};
+
+    pub const _SC_NPROCESSORS_ONLN: c_int = 58;
+
+    extern {
+        pub fn sysconf(name: c_int) -> c_long;
+    }
+
+    // OS X only takes affinity hints through the thread_policy API
+    pub fn set_cpu(_cpu: usize) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "CPU affinity is not supported on this platform"))
+    }
+
+    pub type Mask = ();
+
+    pub fn mask() -> io::Result<Mask> {
+        Err(io::Error::new(io::ErrorKind::Other, "CPU affinity is not supported on this platform"))
+    }
+
+    pub fn set_mask(_mask: &Mask) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "CPU affinity is not supported on this platform"))
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+mod imp {
+    use std::io;
+
+    use libc::{c_int, c_long};
+
+    pub const _SC_NPROCESSORS_ONLN: c_int = 0;
+
+    // The number of online cores is unknown here, `cpus` takes it as one
+    pub unsafe fn sysconf(_name: c_int) -> c_long {
+        -1
+    }
+
+    pub fn set_cpu(_cpu: usize) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "CPU affinity is not supported on this platform"))
+    }
+
+    pub type Mask = ();
+
+    pub fn mask() -> io::Result<Mask> {
+        Err(io::Error::new(io::ErrorKind::Other, "CPU affinity is not supported on this platform"))
+    }
+
+    pub fn set_mask(_mask: &Mask) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "CPU affinity is not supported on this platform"))
+    }
+}
@@ -0,0 +1,204 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Thread pool for running blocking works outside of the processors
+
+use std::collections::VecDeque;
+use std::rt;
+use std::sync::{Arc, Mutex, Condvar};
+use std::thread;
+use std::time::Duration;
+
+use sync::waiter;
+
+// Idle threads exit after this, so a burst of blocking works won't keep the threads forever
+const KEEP_ALIVE_MS: u64 = 10_000;
+
+type Job = Box<FnMut() + Send>;
+
+struct State {
+    jobs: VecDeque<Job>,
+    threads: usize,
+    idle: usize,
+}
+
+struct Inner {
+    state: Mutex<State>,
+    cond: Condvar,
+    max_threads: usize,
+}
+
+#[doc(hidden)]
+/// Pool of at most `max_threads` threads, which are started on demand
+pub struct BlockingPool {
+    inner: Arc<Inner>,
+}
+
+impl BlockingPool {
+    pub fn new(max_threads: usize) -> BlockingPool {
+        BlockingPool {
+            inner: Arc::new(Inner {
+                state: Mutex::new(State {
+                    jobs: VecDeque::new(),
+                    threads: 0,
+                    idle: 0,
+                }),
+                cond: Condvar::new(),
+                max_threads: if max_threads == 0 { 1 } else { max_threads },
+            }),
+        }
+    }
+
+    /// Run `f` in the pool, it waits in the queue if all the threads are busy
+    pub fn execute<F>(&self, f: F)
+        where F: FnOnce() + Send + 'static
+    {
+        let mut state = self.inner.state.lock().unwrap();
+        let mut f = Some(f);
+        state.jobs.push_back(Box::new(move|| {
+            if let Some(f) = f.take() {
+                f();
+            }
+        }));
+
+        if state.idle >= state.jobs.len() || state.threads >= self.inner.max_threads {
+            self.inner.cond.notify_one();
+            return;
+        }
+
+        state.threads += 1;
+        let inner = self.inner.clone();
+        let spawned = thread::Builder::new()
+            .name("simplesched-blocking".to_owned())
+            .spawn(move|| inner.work());
+
+        if let Err(err) = spawned {
+            state.threads -= 1;
+            error!("Failed to spawn a blocking thread: {:?}", err);
+            // The job is still run by one of the existing threads, if any
+            self.inner.cond.notify_one();
+        }
+    }
+
+    /// Run `f` in the pool and block the current coroutine until it returns, a panic
+    /// inside of `f` is returned as an error
+    ///
+    /// Outside of any coroutine, the current thread is blocked instead.
+    pub fn run<F, T>(&self, f: F) -> thread::Result<T>
+        where F: FnOnce() -> T + Send + 'static,
+              T: Send + 'static
+    {
+        let result: Arc<Mutex<Option<thread::Result<T>>>> = Arc::new(Mutex::new(None));
+
+        {
+            let result = result.clone();
+            waiter::wait(move|waiter| {
+                self.execute(move|| {
+                    let mut ret = None;
+                    let panicked = unsafe { rt::unwind::try(|| ret = Some(f())) };
+                    *result.lock().unwrap() = Some(panicked.map(|_| ret.unwrap()));
+
+                    // Goes back to the scheduler with `Scheduler::ready`
+                    waiter.notify();
+                });
+            });
+        }
+
+        let ret = result.lock().unwrap().take();
+        ret.expect("blocking work was not finished")
+    }
+}
+
+impl Inner {
+    fn work(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            match state.jobs.pop_front() {
+                Some(mut job) => {
+                    drop(state);
+                    job();
+                    state = self.state.lock().unwrap();
+                },
+                None => {
+                    state.idle += 1;
+                    let keep_alive = Duration::from_millis(KEEP_ALIVE_MS);
+                    let (guard, timeout) = self.cond.wait_timeout(state, keep_alive).unwrap();
+                    state = guard;
+                    state.idle -= 1;
+
+                    if !timeout.timed_out() || !state.jobs.is_empty() {
+                        continue;
+                    }
+
+                    state.threads -= 1;
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use runtime::{Runtime, Config};
+    use scheduler::Scheduler;
+
+    #[test]
+    fn test_spawn_blocking() {
+        let runtime = Runtime::new(Config::new().blocking_threads(1));
+        let ticks = Arc::new(AtomicUsize::new(0));
+
+        let their_ticks = ticks.clone();
+        let ticker = runtime.spawn(move|| {
+            for _ in 0..5 {
+                their_ticks.fetch_add(1, Ordering::SeqCst);
+                Scheduler::sleep(Duration::from_millis(1));
+            }
+        });
+
+        let their_ticks = ticks.clone();
+        let hdl = runtime.spawn(move|| {
+            // The only processor keeps running the ticker while this coroutine is blocked
+            let ticked = Scheduler::spawn_blocking(move|| {
+                while their_ticks.load(Ordering::SeqCst) < 5 {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                thread::current().name().map(|s| s.to_owned())
+            });
+
+            let panicked = Scheduler::spawn_blocking(|| panic!("Panicked inside blocking work"));
+            (ticked.unwrap(), panicked.is_err())
+        });
+
+        runtime.run();
+
+        ticker.join().unwrap();
+        let (name, panicked) = hdl.join().unwrap();
+        assert_eq!(name, Some("simplesched-blocking".to_owned()));
+        assert!(panicked);
+    }
+}
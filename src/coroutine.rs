@@ -20,18 +20,24 @@
 // CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
 use std::rt;
-use std::cell::UnsafeCell;
 use std::mem;
 use std::any::Any;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use std::io;
+use std::fmt;
 
 use context::{Context, Stack};
-use context::stack::StackPool;
 use context::thunk::Thunk;
 
 use processor::Processor;
-use options::Options;
+use scheduler::Scheduler;
+use options::{Options, Priority};
+use join_handle::{Join, Cancel};
+use coroutine_local::LocalMap;
+use dump::Status;
 
-thread_local!(static STACK_POOL: UnsafeCell<StackPool> = UnsafeCell::new(StackPool::new()));
+static NEXT_COROUTINE_ID: AtomicUsize = ATOMIC_USIZE_INIT;
 
 /// Initialization function for make context
 extern "C" fn coroutine_initialize(_: usize, f: *mut ()) -> ! {
@@ -60,49 +66,237 @@ extern "C" fn coroutine_initialize(_: usize, f: *mut ()) -> ! {
 
 pub type Handle = Box<Coroutine>;
 
+/// Identity of a coroutine, returned by `simplesched::current`
+#[derive(Clone, Debug)]
+pub struct CoroutineInfo {
+    id: usize,
+    name: Option<Arc<String>>,
+}
+
+thread_local!(static THREAD_INFO: CoroutineInfo = CoroutineInfo::new(None));
+
+impl CoroutineInfo {
+    fn new(name: Option<String>) -> CoroutineInfo {
+        CoroutineInfo {
+            id: NEXT_COROUTINE_ID.fetch_add(1, Ordering::Relaxed) + 1,
+            name: name.map(Arc::new),
+        }
+    }
+
+    /// Information of the current thread, which has no processor
+    pub fn of_thread() -> CoroutineInfo {
+        THREAD_INFO.with(|info| info.clone())
+    }
+
+    /// Unique ID of the coroutine
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Name of the coroutine, set by `Options::name`
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_ref().map(|name| &name[..])
+    }
+}
+
+impl fmt::Display for CoroutineInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name {
+            Some(ref name) => write!(f, "Coroutine #{} ({})", self.id, name),
+            None => write!(f, "Coroutine #{}", self.id),
+        }
+    }
+}
+
 /// Coroutine is nothing more than a context and a stack
 pub struct Coroutine {
+    info: CoroutineInfo,
+    status: Arc<Status>,
     context: Context,
     stack: Option<Stack>,
+    join: Option<Arc<Join>>,
+    cancel: Option<Arc<Cancel>>,
+    wakeup_error: Option<io::Error>,
+    scheduler: Arc<Scheduler>,
+    locals: LocalMap,
+    pinned: Option<usize>,
+    pin_on_resume: bool,
+    priority: Priority,
+    abandoned: bool,
+    registry_stripe: usize,
+    generator_guard: Option<(usize, usize)>,
 }
 
 impl Coroutine {
-    pub unsafe fn empty() -> Handle {
+    pub unsafe fn empty(scheduler: Arc<Scheduler>) -> Handle {
+        let info = CoroutineInfo::new(None);
         Box::new(Coroutine {
+            status: Arc::new(Status::new(info.clone())),
+            info: info,
             context: Context::empty(),
             stack: None,
+            join: None,
+            cancel: None,
+            wakeup_error: None,
+            scheduler: scheduler,
+            locals: LocalMap::new(),
+            pinned: None,
+            pin_on_resume: false,
+            priority: Priority::Normal,
+            abandoned: false,
+            registry_stripe: 0,
+            generator_guard: None,
         })
     }
 
-    pub fn spawn_opts<F>(f: F, opts: Options) -> Handle
+    pub fn spawn_opts<F>(f: F, opts: Options, scheduler: Arc<Scheduler>) -> Handle
         where F: FnOnce() + Send + 'static
     {
-        let mut stack = STACK_POOL.with(|pool| unsafe {
-            (&mut *pool.get()).take_stack(opts.stack_size)
-        });
+        let mut stack = scheduler.stack_pool().take_stack(opts.stack_size);
 
         let ctx = Context::new(coroutine_initialize, 0, f, &mut stack);
+        let info = CoroutineInfo::new(opts.name);
         Box::new(Coroutine {
+            status: Arc::new(Status::new(info.clone())),
+            info: info,
             context: ctx,
             stack: Some(stack),
+            join: None,
+            cancel: None,
+            wakeup_error: None,
+            scheduler: scheduler,
+            locals: LocalMap::new(),
+            pinned: opts.processor,
+            pin_on_resume: opts.pinned,
+            priority: opts.priority,
+            abandoned: false,
+            registry_stripe: 0,
+            generator_guard: None,
         })
     }
 
+    pub fn info(&self) -> &CoroutineInfo {
+        &self.info
+    }
+
+    /// Address range of the guard page at the low end of the stack
+    pub fn guard(&self) -> Option<(usize, usize)> {
+        self.stack.as_ref().map(|stack| (stack.start() as usize, stack.guard() as usize))
+    }
+
+    /// Guard page of the stack this coroutine is running on, which is the stack of a
+    /// generator while it is inside a generator body
+    pub fn running_guard(&self) -> Option<(usize, usize)> {
+        self.generator_guard.or_else(|| self.guard())
+    }
+
+    /// Set the guard page of the generator running in this coroutine, returns the previous one
+    pub fn replace_generator_guard(&mut self, guard: Option<(usize, usize)>) -> Option<(usize, usize)> {
+        mem::replace(&mut self.generator_guard, guard)
+    }
+
+    /// State of this coroutine for `Scheduler::dump`
+    pub fn status(&self) -> &Arc<Status> {
+        &self.status
+    }
+
+    /// The scheduler which this coroutine belongs to
+    pub fn scheduler(&self) -> &Arc<Scheduler> {
+        &self.scheduler
+    }
+
     pub fn yield_to(&mut self, target: &Coroutine) {
         Context::swap(&mut self.context, &target.context);
     }
+
+    /// Set the packet which will be notified when this coroutine terminates
+    pub fn set_join(&mut self, join: Arc<Join>) {
+        self.join = Some(join);
+    }
+
+    /// Values of the coroutine local keys
+    pub fn locals(&mut self) -> &mut LocalMap {
+        &mut self.locals
+    }
+
+    /// Set the cancellation state shared with the `JoinHandle`
+    pub fn set_cancel(&mut self, cancel: Arc<Cancel>) {
+        self.cancel = Some(cancel);
+    }
+
+    pub fn cancel(&self) -> Option<&Arc<Cancel>> {
+        self.cancel.as_ref()
+    }
+
+    /// ID of the processor which this coroutine must be resumed on
+    pub fn pinned(&self) -> Option<usize> {
+        self.pinned
+    }
+
+    pub fn set_pinned(&mut self, processor_id: Option<usize>) {
+        self.pinned = processor_id;
+    }
+
+    /// Whether this coroutine should be pinned to the processor which resumes it first
+    pub fn pin_on_resume(&self) -> bool {
+        self.pin_on_resume
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// Whether the `JoinHandle` of this coroutine has been aborted
+    pub fn is_aborted(&self) -> bool {
+        self.cancel.as_ref().map_or(false, |c| c.is_cancelled())
+    }
+
+    /// Set the error for a blocked coroutine which is waked up without the event it waits for
+    pub fn set_wakeup_error(&mut self, err: io::Error) {
+        self.wakeup_error = Some(err);
+    }
+
+    /// Take the error set by `set_wakeup_error`
+    pub fn take_wakeup_error(&mut self) -> Option<io::Error> {
+        self.wakeup_error.take()
+    }
+
+    /// Notify the joiner, `panic` is the payload if the coroutine panicked
+    pub fn finish(&mut self, panic: Option<Box<Any + Send + 'static>>) {
+        if let Some(join) = self.join.take() {
+            join.finish(panic);
+        }
+    }
+
+    /// Finish a coroutine which will never be resumed, because its scheduler has shut down
+    pub fn abandon(&mut self) {
+        self.abandoned = true;
+        // The waker lives on the stack of this coroutine
+        if let Some(cancel) = self.cancel.as_ref() {
+            cancel.clear_waker();
+        }
+        self.finish(Some(Box::new("the scheduler has shut down")));
+    }
+
+    pub fn is_abandoned(&self) -> bool {
+        self.abandoned
+    }
+
+    /// Stripe of the scheduler's registry which this coroutine is kept in
+    pub fn registry_stripe(&self) -> usize {
+        self.registry_stripe
+    }
+
+    pub fn set_registry_stripe(&mut self, stripe: usize) {
+        self.registry_stripe = stripe;
+    }
 }
 
 impl Drop for Coroutine {
     fn drop(&mut self) {
         match self.stack.take() {
             None => {},
-            Some(st) => {
-                STACK_POOL.with(|pool| unsafe {
-                    let pool: &mut StackPool = mem::transmute(pool.get());
-                    pool.give_stack(st);
-                })
-            }
+            Some(st) => self.scheduler.stack_pool().give_stack(st),
         }
     }
 }
@@ -121,3 +315,62 @@ pub enum Error {
 }
 
 pub type Result<T> = ::std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use options::Options;
+    use runtime::Config;
+    use scheduler::Scheduler;
+
+    use super::Coroutine;
+
+    // Permissions of the mapping containing `addr` in `/proc/self/maps`, like `r-xp`
+    #[cfg(target_os = "linux")]
+    fn permissions(addr: usize) -> Option<String> {
+        use std::fs::File;
+        use std::io::Read;
+
+        let mut maps = String::new();
+        File::open("/proc/self/maps").unwrap().read_to_string(&mut maps).unwrap();
+        for line in maps.lines() {
+            let mut words = line.split_whitespace();
+            let range = words.next().unwrap();
+            let mut bounds = range.split('-').map(|b| usize::from_str_radix(b, 16).unwrap());
+            let (start, end) = (bounds.next().unwrap(), bounds.next().unwrap());
+            if addr >= start && addr < end {
+                return words.next().map(|p| p.to_owned());
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn test_guard() {
+        let scheduler = Arc::new(Scheduler::new(&Config::new()));
+
+        let coro = Coroutine::spawn_opts(|| {}, Options::new(), scheduler.clone());
+        let (start, end) = coro.guard().unwrap();
+        {
+            let stack = coro.stack.as_ref().unwrap();
+            assert_eq!(start, stack.start() as usize);
+            assert_eq!(end, stack.guard() as usize);
+        }
+        assert!(start < end);
+
+        #[cfg(target_os = "linux")]
+        fn check_protected(start: usize, end: usize) {
+            assert_eq!(permissions(start).unwrap(), "---p");
+            assert_eq!(permissions(end - 1).unwrap(), "---p");
+            assert!(permissions(end).unwrap() != "---p");
+        }
+        #[cfg(not(target_os = "linux"))]
+        fn check_protected(_start: usize, _end: usize) {}
+        check_protected(start, end);
+
+        // The main coroutine of a processor runs on the thread's stack
+        let empty = unsafe { Coroutine::empty(scheduler) };
+        assert_eq!(empty.guard(), None);
+    }
+}
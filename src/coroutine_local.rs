@@ -0,0 +1,141 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Coroutine local storage
+//!
+//! Coroutines may be resumed by different threads, so `thread_local!` values are not
+//! reliable inside them. Values declared by `coroutine_local!` live in the coroutine
+//! instead, they are initialized lazily on first access in every coroutine, and are
+//! dropped with the coroutine when it finishes.
+//!
+//! ```ignore
+//! coroutine_local!(static REQUEST_ID: Cell<u64> = Cell::new(0));
+//!
+//! REQUEST_ID.with(|id| id.set(42));
+//! ```
+//!
+//! Outside of any coroutine, the values belong to the thread.
+
+use std::any::Any;
+use std::collections::HashMap;
+
+use processor::Processor;
+
+/// Values of the coroutine local keys in a coroutine
+pub type LocalMap = HashMap<usize, Box<Any + Send>>;
+
+/// Declare a new coroutine local storage key of type `simplesched::coroutine_local::LocalKey`
+#[macro_export]
+macro_rules! coroutine_local {
+    ($(#[$attr:meta])* static $name:ident: $t:ty = $init:expr) => (
+        $(#[$attr])* static $name: $crate::coroutine_local::LocalKey<$t> = {
+            fn __init() -> $t { $init }
+            $crate::coroutine_local::LocalKey { __init: __init }
+        };
+    );
+    ($(#[$attr:meta])* pub static $name:ident: $t:ty = $init:expr) => (
+        $(#[$attr])* pub static $name: $crate::coroutine_local::LocalKey<$t> = {
+            fn __init() -> $t { $init }
+            $crate::coroutine_local::LocalKey { __init: __init }
+        };
+    );
+}
+
+/// A key for the coroutine local storage, declared by `coroutine_local!`
+pub struct LocalKey<T: Send + 'static> {
+    #[doc(hidden)]
+    pub __init: fn() -> T,
+}
+
+impl<T: Send + 'static> LocalKey<T> {
+    /// Acquire a reference to the value of this key in the current coroutine
+    ///
+    /// The value is initialized if this is the first access in the current coroutine.
+    pub fn with<F, R>(&'static self, f: F) -> R
+        where F: FnOnce(&T) -> R
+    {
+        let coro = Processor::current().current_coroutine();
+        let key = self as *const LocalKey<T> as usize;
+
+        let value: *const T = unsafe {
+            if !(*coro).locals().contains_key(&key) {
+                // The initializer may access the other keys
+                let value = (self.__init)();
+                (*coro).locals().insert(key, Box::new(value));
+            }
+
+            (*coro).locals()[&key].downcast_ref::<T>().unwrap()
+        };
+
+        // The value is boxed and won't be removed until the coroutine is dropped
+        f(unsafe { &*value })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::{Cell, RefCell};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use scheduler::Scheduler;
+    use runtime::{Runtime, Config};
+
+    struct Counted(Arc<AtomicUsize>);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    coroutine_local!(static ID: Cell<usize> = Cell::new(0));
+    coroutine_local!(static COUNTED: RefCell<Option<Counted>> = RefCell::new(None));
+
+    #[test]
+    fn test_coroutine_local() {
+        let runtime = Runtime::new(Config::new().threads(4));
+        let dropped = Arc::new(AtomicUsize::new(0));
+
+        let hdls: Vec<_> = (1..11).map(|id| {
+            let dropped = dropped.clone();
+            runtime.spawn(move|| {
+                assert_eq!(ID.with(|v| v.get()), 0);
+                ID.with(|v| v.set(id));
+                COUNTED.with(|v| *v.borrow_mut() = Some(Counted(dropped)));
+
+                for _ in 0..10 {
+                    Scheduler::sched();
+                    assert_eq!(ID.with(|v| v.get()), id);
+                }
+
+                ID.with(|v| v.get())
+            })
+        }).collect();
+
+        runtime.run();
+
+        for (id, hdl) in (1..11).zip(hdls.into_iter()) {
+            assert_eq!(hdl.join().unwrap(), id);
+        }
+        assert_eq!(dropped.load(Ordering::SeqCst), 10);
+    }
+}
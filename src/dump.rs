@@ -0,0 +1,349 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Dump of the live coroutines, for debugging hung services
+//!
+//! `Scheduler::dump` lists the coroutines of a scheduler. After `dump_on_sigusr1` has been
+//! called, sending `SIGUSR1` to the process makes every running scheduler print the list
+//! to the standard error.
+//!
+//! The signal handler only writes to a pipe, a dedicated thread reads it and prints the
+//! dumps, so they are printed even if every processor is stuck in a busy coroutine.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::os::unix::io::RawFd;
+use std::sync::{Mutex, Once, ONCE_INIT};
+use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc;
+use mio::EventSet;
+
+use coroutine::CoroutineInfo;
+use scheduler::Scheduler;
+
+/// A scheduler which is in `Scheduler::run_threads`
+struct Running(*const Scheduler);
+
+unsafe impl Send for Running {}
+
+lazy_static! {
+    static ref EPOCH: Instant = Instant::now();
+    static ref RUNNING: Mutex<Vec<Running>> = Mutex::new(Vec::new());
+}
+
+// Write end of the pipe which wakes up the dumping thread
+static PIPE_WRITE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+#[cfg(all(any(target_os = "linux",
+              target_os = "android"),
+          not(any(target_arch = "mips",
+                  target_arch = "mipsel"))))]
+mod consts {
+    use libc::c_int;
+
+    pub const SIGUSR1: c_int = 10;
+    pub const O_NONBLOCK: c_int = 0o4000;
+}
+
+#[cfg(all(any(target_os = "linux",
+              target_os = "android"),
+          any(target_arch = "mips",
+              target_arch = "mipsel")))]
+mod consts {
+    use libc::c_int;
+
+    pub const SIGUSR1: c_int = 16;
+    pub const O_NONBLOCK: c_int = 0x80;
+}
+
+#[cfg(any(target_os = "macos",
+          target_os = "ios",
+          target_os = "freebsd",
+          target_os = "dragonfly",
+          target_os = "bitrig",
+          target_os = "openbsd"))]
+mod consts {
+    use libc::c_int;
+
+    pub const SIGUSR1: c_int = 30;
+    pub const O_NONBLOCK: c_int = 0x4;
+}
+
+use self::consts::{SIGUSR1, O_NONBLOCK};
+
+const F_GETFL: libc::c_int = 3;
+const F_SETFL: libc::c_int = 4;
+
+#[allow(non_camel_case_types)]
+type sighandler_t = libc::size_t;
+
+const SIG_ERR: sighandler_t = !0;
+
+extern {
+    fn signal(signum: libc::c_int, handler: sighandler_t) -> sighandler_t;
+    fn pipe(fds: *mut libc::c_int) -> libc::c_int;
+    fn fcntl(fd: libc::c_int, cmd: libc::c_int, ...) -> libc::c_int;
+    fn read(fd: libc::c_int, buf: *mut libc::c_void, count: libc::size_t) -> libc::ssize_t;
+    fn write(fd: libc::c_int, buf: *const libc::c_void, count: libc::size_t) -> libc::ssize_t;
+}
+
+extern "C" fn on_sigusr1(_: libc::c_int) {
+    // The pipe is non-blocking, signals coming while it is full are merged
+    let byte = 0u8;
+    unsafe {
+        write(PIPE_WRITE.load(Ordering::SeqCst) as libc::c_int,
+              &byte as *const u8 as *const libc::c_void, 1);
+    }
+}
+
+/// Print the live coroutines of every running scheduler to the standard error when the
+/// process receives `SIGUSR1`
+///
+/// The previous handler of `SIGUSR1` is replaced.
+pub fn dump_on_sigusr1() {
+    static INSTALL: Once = ONCE_INIT;
+    INSTALL.call_once(|| {
+        if let Err(err) = start_dump_thread() {
+            error!("Failed to start the thread dumping on SIGUSR1: {}", err);
+            return;
+        }
+
+        if unsafe { signal(SIGUSR1, on_sigusr1 as sighandler_t) } == SIG_ERR {
+            error!("Failed to install the SIGUSR1 handler: {}", io::Error::last_os_error());
+        }
+    });
+}
+
+fn start_dump_thread() -> io::Result<()> {
+    // Initialize it outside of the signal handler
+    let _ = *EPOCH;
+
+    let mut fds = [0; 2];
+    unsafe {
+        if pipe(fds.as_mut_ptr()) == -1
+                || fcntl(fds[1], F_SETFL, fcntl(fds[1], F_GETFL) | O_NONBLOCK) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    PIPE_WRITE.store(fds[1] as usize, Ordering::SeqCst);
+
+    let read_fd = fds[0];
+    try!(thread::Builder::new().name("simplesched-dump".to_owned()).spawn(move|| {
+        let mut byte = 0u8;
+        loop {
+            let ret = unsafe { read(read_fd, &mut byte as *mut u8 as *mut libc::c_void, 1) };
+            if ret == 1 {
+                for scheduler in RUNNING.lock().unwrap().iter() {
+                    print(&unsafe { &*scheduler.0 }.dump());
+                }
+            } else if ret == -1 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                continue;
+            } else {
+                error!("Failed to read the SIGUSR1 pipe: {}", io::Error::last_os_error());
+                break;
+            }
+        }
+    }));
+
+    Ok(())
+}
+
+#[doc(hidden)]
+/// Make `scheduler` printed on `SIGUSR1` until it is unregistered
+pub fn register(scheduler: &Scheduler) {
+    RUNNING.lock().unwrap().push(Running(scheduler));
+}
+
+#[doc(hidden)]
+pub fn unregister(scheduler: &Scheduler) {
+    let mut running = RUNNING.lock().unwrap();
+    if let Some(idx) = running.iter().position(|r| r.0 == scheduler as *const Scheduler) {
+        running.remove(idx);
+    }
+}
+
+/// State of a live coroutine
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CoroutineState {
+    /// Waiting in a queue to be resumed
+    Suspended,
+    /// Being run by a processor
+    Running,
+    /// Waiting for I/O, a timer or another coroutine
+    Blocked,
+}
+
+/// A live coroutine, returned by `Scheduler::dump`
+#[derive(Clone, Debug)]
+pub struct CoroutineDump {
+    pub id: usize,
+    pub name: Option<String>,
+    pub state: CoroutineState,
+    /// The fd and the events the coroutine is waiting for in `wait_event`
+    pub wait_event: Option<(RawFd, EventSet)>,
+    /// How long the coroutine has been blocked
+    pub blocked_for: Option<Duration>,
+}
+
+impl fmt::Display for CoroutineDump {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "Coroutine #{}", self.id));
+        if let Some(ref name) = self.name {
+            try!(write!(f, " ({})", name));
+        }
+        try!(write!(f, " {:?}", self.state));
+        if let Some(dur) = self.blocked_for {
+            try!(write!(f, " for {}.{:03}s", dur.as_secs(), dur.subsec_nanos() / 1_000_000));
+        }
+        if let Some((fd, interest)) = self.wait_event {
+            try!(write!(f, " on fd {} {:?}", fd, interest));
+        }
+        Ok(())
+    }
+}
+
+const SUSPENDED: usize = 0;
+const RUNNING: usize = 1;
+const BLOCKED: usize = 2;
+
+fn now_ms() -> usize {
+    let dur = EPOCH.elapsed();
+    dur.as_secs() as usize * 1_000 + dur.subsec_nanos() as usize / 1_000_000
+}
+
+#[doc(hidden)]
+/// State of a coroutine which is shared with the dumping thread
+pub struct Status {
+    info: CoroutineInfo,
+    state: AtomicUsize,
+    blocked_since: AtomicUsize,
+    wait_event: Mutex<Option<(RawFd, EventSet)>>,
+}
+
+impl Status {
+    pub fn new(info: CoroutineInfo) -> Status {
+        Status {
+            info: info,
+            state: AtomicUsize::new(SUSPENDED),
+            blocked_since: AtomicUsize::new(0),
+            wait_event: Mutex::new(None),
+        }
+    }
+
+    pub fn set_running(&self) {
+        self.state.store(RUNNING, Ordering::Relaxed);
+    }
+
+    pub fn set_suspended(&self) {
+        self.state.store(SUSPENDED, Ordering::Relaxed);
+    }
+
+    pub fn set_blocked(&self) {
+        self.blocked_since.store(now_ms(), Ordering::Relaxed);
+        self.state.store(BLOCKED, Ordering::Relaxed);
+    }
+
+    pub fn set_wait_event(&self, wait_event: Option<(RawFd, EventSet)>) {
+        *self.wait_event.lock().unwrap() = wait_event;
+    }
+
+    pub fn dump(&self) -> CoroutineDump {
+        let state = match self.state.load(Ordering::Relaxed) {
+            RUNNING => CoroutineState::Running,
+            BLOCKED => CoroutineState::Blocked,
+            _ => CoroutineState::Suspended,
+        };
+
+        let blocked_for = match state {
+            CoroutineState::Blocked => {
+                let ms = now_ms().saturating_sub(self.blocked_since.load(Ordering::Relaxed));
+                Some(Duration::from_millis(ms as u64))
+            },
+            _ => None,
+        };
+
+        CoroutineDump {
+            id: self.info.id(),
+            name: self.info.name().map(|name| name.to_owned()),
+            state: state,
+            wait_event: *self.wait_event.lock().unwrap(),
+            blocked_for: blocked_for,
+        }
+    }
+}
+
+#[doc(hidden)]
+/// Print the dump to the standard error
+pub fn print(dump: &[CoroutineDump]) {
+    let stderr = io::stderr();
+    let mut stderr = stderr.lock();
+
+    let _ = writeln!(stderr, "{} live coroutines:", dump.len());
+    for coro in dump.iter() {
+        let _ = writeln!(stderr, "    {}", coro);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use mio::EventSet;
+
+    use scheduler::Scheduler;
+    use runtime::{Runtime, Config};
+    use options::Options;
+    use net::tcp::TcpListener;
+
+    use super::CoroutineState;
+
+    #[test]
+    fn test_dump() {
+        let runtime = Runtime::new(Config::new());
+
+        let hdl = runtime.spawn(|| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let acceptor = Scheduler::spawn_opts(move|| { let _ = listener.accept(); },
+                                                 Options::new().name(Some("acceptor".to_owned())));
+
+            Scheduler::sleep(Duration::from_millis(20));
+            let dump = Scheduler::get().dump();
+            acceptor.abort();
+
+            dump
+        });
+
+        runtime.run();
+
+        let dump = hdl.join().unwrap();
+        assert_eq!(dump.len(), 2);
+        assert_eq!(dump[0].state, CoroutineState::Running);
+
+        let acceptor = &dump[1];
+        assert_eq!(acceptor.name, Some("acceptor".to_owned()));
+        assert_eq!(acceptor.state, CoroutineState::Blocked);
+        assert_eq!(acceptor.wait_event.map(|(_, interest)| interest), Some(EventSet::readable()));
+        assert!(acceptor.blocked_for.unwrap() >= Duration::from_millis(10));
+    }
+}
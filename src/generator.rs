@@ -0,0 +1,332 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Generators, coroutines which yield values to their caller
+//!
+//! A generator runs on its own stack, but it is switched to and from directly by the
+//! caller instead of going through the scheduler. If the body blocks inside a coroutine,
+//! the calling coroutine is blocked with it.
+//!
+//! ```ignore
+//! let gen = Generator::new(|y| {
+//!     for i in 0..3 {
+//!         y.yield_(i);
+//!     }
+//! });
+//! assert_eq!(gen.collect::<Vec<_>>(), [0, 1, 2]);
+//! ```
+
+use std::any::Any;
+use std::mem;
+use std::rt;
+use std::sync::Arc;
+use std::thread;
+
+use context::{Context, Stack};
+use context::thunk::Thunk;
+
+use scheduler::Scheduler;
+use processor::Processor;
+use stack_pool::StackPool;
+use stack_guard;
+use join_handle::Aborted;
+
+/// Initialization function for the context of a generator, the body never returns
+extern "C" fn generator_initialize(_: usize, f: *mut ()) -> ! {
+    unsafe {
+        let func: Box<Thunk> = mem::transmute(f);
+        func.invoke(());
+    }
+
+    unreachable!("a finished generator is resumed");
+}
+
+/// Panic payload which unwinds the body of a generator dropped before finishing
+struct Cancelled;
+
+type Body<T> = Box<FnMut(&mut Yielder<T>) + Send>;
+
+struct Inner<T> {
+    context: Context,
+    caller: Context,
+    stack: Option<Stack>,
+    body: Option<Body<T>>,
+    value: Option<T>,
+    finished: bool,
+    cancelled: bool,
+    panic: Option<Box<Any + Send>>,
+}
+
+/// A coroutine which produces values of `T` for the caller of `next`
+pub struct Generator<T> {
+    inner: Box<Inner<T>>,
+    stack_pool: Arc<StackPool>,
+}
+
+impl<T: 'static> Generator<T> {
+    /// Create a generator with the default stack size, `f` runs on the first `next`
+    pub fn new<F>(f: F) -> Generator<T>
+        where F: FnOnce(&mut Yielder<T>) + Send + 'static
+    {
+        Generator::with_stack_size(rt::min_stack(), f)
+    }
+
+    /// Create a generator whose stack is `size` bytes
+    pub fn with_stack_size<F>(size: usize, f: F) -> Generator<T>
+        where F: FnOnce(&mut Yielder<T>) + Send + 'static
+    {
+        let stack_pool = Scheduler::get().stack_pool().clone();
+
+        let mut f = Some(f);
+        let body: Body<T> = Box::new(move|y| {
+            if let Some(f) = f.take() {
+                f(y);
+            }
+        });
+
+        Generator {
+            inner: Box::new(Inner {
+                context: Context::empty(),
+                caller: Context::empty(),
+                stack: Some(stack_pool.take_stack(size)),
+                body: Some(body),
+                value: None,
+                finished: false,
+                cancelled: false,
+                panic: None,
+            }),
+            stack_pool: stack_pool,
+        }
+    }
+
+    /// Whether the body has returned
+    pub fn is_finished(&self) -> bool {
+        self.inner.finished
+    }
+
+    /// Run the body until it yields the next value or returns
+    ///
+    /// A panic of the body is propagated to the caller. Aborting the calling coroutine while
+    /// the body is blocked unwinds the caller with `Aborted`.
+    pub fn resume(&mut self) -> Option<T> {
+        if self.inner.finished {
+            return None;
+        }
+
+        if let Some(body) = self.inner.body.take() {
+            self.start(body);
+        }
+        self.switch_in();
+
+        if let Some(err) = self.inner.panic.take() {
+            // The payload can't be unwound again as it is, but `Aborted` has to be kept so
+            // the caller is finished as an aborted coroutine
+            if err.is::<Aborted>() {
+                panic!(Aborted);
+            }
+
+            let msg = match err.downcast_ref::<&'static str>() {
+                Some(s) => *s,
+                None => match err.downcast_ref::<String>() {
+                    Some(s) => &s[..],
+                    None => "Box<Any>",
+                },
+            };
+            panic!("generator panicked: {}", msg);
+        }
+
+        self.inner.value.take()
+    }
+
+    fn start(&mut self, mut body: Body<T>) {
+        let inner_ptr = &mut *self.inner as *mut Inner<T> as usize;
+
+        let inner = &mut *self.inner;
+        inner.context = Context::new(generator_initialize, 0, move|| {
+            let mut yielder = Yielder { inner: inner_ptr as *mut Inner<T> };
+            let ret = unsafe { rt::unwind::try(move|| body(&mut yielder)) };
+
+            let inner = unsafe { &mut *(inner_ptr as *mut Inner<T>) };
+            if let Err(err) = ret {
+                if !err.is::<Cancelled>() {
+                    inner.panic = Some(err);
+                }
+            }
+            inner.finished = true;
+
+            loop {
+                Context::swap(&mut inner.context, &inner.caller);
+            }
+        }, inner.stack.as_mut().unwrap());
+    }
+}
+
+impl<T> Generator<T> {
+    fn switch_in(&mut self) {
+        let inner = &mut *self.inner;
+        let guard = inner.stack.as_ref().map(|stack| (stack.start() as usize, stack.guard() as usize));
+
+        // The body may block, then the coroutine is resumed on the generator's stack
+        let coro = Processor::try_current().and_then(|p| p.running());
+        let prev_guard = coro.and_then(|coro| unsafe {
+            (&mut *coro.coro_ptr).replace_generator_guard(guard)
+        });
+        let (caller_guard, info) = stack_guard::running();
+        stack_guard::set_running(guard, info);
+
+        Context::swap(&mut inner.caller, &inner.context);
+
+        if let Some(coro) = coro {
+            unsafe { (&mut *coro.coro_ptr).replace_generator_guard(prev_guard); }
+        }
+        stack_guard::set_running(caller_guard, info);
+    }
+}
+
+impl<T: 'static> Iterator for Generator<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.resume()
+    }
+}
+
+impl<T> Drop for Generator<T> {
+    fn drop(&mut self) {
+        // Unwind the suspended body, so the values on its stack are dropped. Unwinding it
+        // while this thread is panicking would abort, so they are leaked instead.
+        if self.inner.body.is_none() && !self.inner.finished && !thread::panicking() {
+            self.inner.cancelled = true;
+            self.switch_in();
+        }
+
+        if let Some(stack) = self.inner.stack.take() {
+            self.stack_pool.give_stack(stack);
+        }
+    }
+}
+
+/// Handle for the body of a generator to yield values
+pub struct Yielder<T> {
+    inner: *mut Inner<T>,
+}
+
+impl<T> Yielder<T> {
+    /// Hand `value` to the caller and suspend the body until the next `resume`
+    pub fn yield_(&mut self, value: T) {
+        let inner = unsafe { &mut *self.inner };
+        inner.value = Some(value);
+        Context::swap(&mut inner.context, &inner.caller);
+
+        if inner.cancelled {
+            panic!(Cancelled);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    use runtime::{Runtime, Config};
+    use scheduler::Scheduler;
+    use join_handle::Aborted;
+
+    use super::Generator;
+
+    #[test]
+    fn test_generator() {
+        let fib = Generator::new(|y| {
+            let (mut a, mut b) = (0, 1);
+            loop {
+                y.yield_(a);
+                let next = a + b;
+                a = b;
+                b = next;
+            }
+        });
+        assert_eq!(fib.take(10).collect::<Vec<u64>>(), [0, 1, 1, 2, 3, 5, 8, 13, 21, 34]);
+
+        // The body may block the calling coroutine
+        let runtime = Runtime::new(Config::new());
+        let hdl = runtime.spawn(|| {
+            let gen = Generator::new(|y| {
+                for i in 0..3 {
+                    Scheduler::sleep(Duration::from_millis(10));
+                    y.yield_(i);
+                }
+            });
+            gen.collect::<Vec<_>>()
+        });
+        runtime.run();
+
+        assert_eq!(hdl.join().unwrap(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn test_drop_unfinished() {
+        struct SetOnDrop(Arc<AtomicBool>);
+
+        impl Drop for SetOnDrop {
+            fn drop(&mut self) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let dropped = Arc::new(AtomicBool::new(false));
+        let dropped_cloned = dropped.clone();
+        let mut gen = Generator::new(move|y| {
+            let _guard = SetOnDrop(dropped_cloned);
+            loop {
+                y.yield_(());
+            }
+        });
+
+        assert_eq!(gen.next(), Some(()));
+        assert!(!dropped.load(Ordering::SeqCst));
+
+        drop(gen);
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_abort_inside_body() {
+        let runtime = Runtime::new(Config::new());
+
+        let hdl = runtime.spawn(|| {
+            let sleeper = Scheduler::spawn(|| {
+                let mut gen = Generator::new(|y| {
+                    Scheduler::sleep(Duration::from_secs(60));
+                    y.yield_(());
+                });
+                gen.next()
+            });
+            sleeper.abort();
+            sleeper.join().unwrap_err().is::<Aborted>()
+        });
+
+        runtime.run();
+
+        assert!(hdl.join().unwrap());
+    }
+}
@@ -0,0 +1,287 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Handle for waiting on a spawned coroutine
+
+use std::any::Any;
+use std::mem;
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use processor::Processor;
+use scheduler::{Scheduler, CoroutineRefMut};
+
+/// Result of a coroutine, `Err` holds the payload of the panic
+pub type Result<T> = ::std::result::Result<T, Box<Any + Send + 'static>>;
+
+#[doc(hidden)]
+/// Type-erased completion of a coroutine, called by the `Processor` when it terminates
+pub trait Join: Send + Sync {
+    fn finish(&self, panic: Option<Box<Any + Send + 'static>>);
+}
+
+struct Inner<T> {
+    result: Option<Result<T>>,
+    finished: bool,
+    waiter: Option<CoroutineRefMut>,
+}
+
+#[doc(hidden)]
+/// Shared state between a coroutine and its `JoinHandle`
+pub struct Packet<T> {
+    inner: Mutex<Inner<T>>,
+    cond: Condvar,
+}
+
+impl<T: Send> Packet<T> {
+    fn new() -> Packet<T> {
+        Packet {
+            inner: Mutex::new(Inner {
+                result: None,
+                finished: false,
+                waiter: None,
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// Store the value returned by the coroutine
+    pub fn set(&self, value: T) {
+        self.inner.lock().unwrap().result = Some(Ok(value));
+    }
+}
+
+impl<T: Send> Join for Packet<T> {
+    fn finish(&self, panic: Option<Box<Any + Send + 'static>>) {
+        let waiter = {
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(err) = panic {
+                inner.result = Some(Err(err));
+            }
+            inner.finished = true;
+            inner.waiter.take()
+        };
+
+        self.cond.notify_all();
+
+        if let Some(coro) = waiter {
+            Scheduler::ready(coro);
+        }
+    }
+}
+
+/// Panic payload of a coroutine which is unwound by `JoinHandle::abort`
+#[derive(Debug)]
+pub struct Aborted;
+
+#[doc(hidden)]
+/// Cancellation state shared between a coroutine and its `JoinHandle`
+pub struct Cancel {
+    cancelled: AtomicBool,
+    waker: Mutex<Option<*const Fn()>>,
+}
+
+unsafe impl Send for Cancel {}
+unsafe impl Sync for Cancel {}
+
+impl Cancel {
+    fn new() -> Cancel {
+        Cancel {
+            cancelled: AtomicBool::new(false),
+            waker: Mutex::new(None),
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Mark as cancelled and wake up the coroutine if it is blocked in `with_waker`
+    pub fn cancel(&self) {
+        let waker = self.waker.lock().unwrap();
+        if self.cancelled.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        // Called with the lock held, so `with_waker` can't return meanwhile
+        if let Some(waker) = *waker {
+            unsafe { (&*waker)(); }
+        }
+    }
+
+    /// Forget the waker of a coroutine which is abandoned inside `with_waker`
+    pub fn clear_waker(&self) {
+        *self.waker.lock().unwrap() = None;
+    }
+
+    /// Run `f`, which blocks the current coroutine, `waker` will be called from the aborting
+    /// thread to wake it up if it is cancelled meanwhile
+    ///
+    /// Returns `None` without calling `f` if it has been cancelled already.
+    pub fn with_waker<W, F, R>(&self, waker: &W, f: F) -> Option<R>
+        where W: Fn(),
+              F: FnOnce() -> R
+    {
+        {
+            let mut guard = self.waker.lock().unwrap();
+            if self.is_cancelled() {
+                return None;
+            }
+
+            let waker: &Fn() = waker;
+            // `waker` outlives the registration, which is removed before returning
+            *guard = Some(unsafe { mem::transmute(waker) });
+        }
+
+        let r = f();
+
+        *self.waker.lock().unwrap() = None;
+        Some(r)
+    }
+}
+
+/// An owned permission to join on a coroutine (block on its termination)
+pub struct JoinHandle<T> {
+    packet: Arc<Packet<T>>,
+    cancel: Arc<Cancel>,
+}
+
+#[doc(hidden)]
+/// Create the packet and the cancellation state for a new coroutine, and the handle that
+/// waits on it
+pub fn handle_pair<T: Send>() -> (Arc<Packet<T>>, Arc<Cancel>, JoinHandle<T>) {
+    let packet = Arc::new(Packet::new());
+    let cancel = Arc::new(Cancel::new());
+    let handle = JoinHandle {
+        packet: packet.clone(),
+        cancel: cancel.clone(),
+    };
+    (packet, cancel, handle)
+}
+
+impl<T: Send> JoinHandle<T> {
+    /// Wait for the coroutine to finish
+    ///
+    /// Inside a coroutine only the calling coroutine is blocked, otherwise the current
+    /// thread is blocked. Returns the payload of the panic if the coroutine panicked.
+    pub fn join(self) -> Result<T> {
+        match Processor::try_current() {
+            Some(processor) if processor.running().is_some() => {
+                if !self.packet.inner.lock().unwrap().finished {
+                    let packet = &self.packet;
+                    processor.block_with(|coro| {
+                        let mut inner = packet.inner.lock().unwrap();
+                        if inner.finished {
+                            drop(inner);
+                            Scheduler::ready(coro);
+                        } else {
+                            inner.waiter = Some(coro);
+                        }
+                    });
+                }
+            },
+            _ => {
+                let mut inner = self.packet.inner.lock().unwrap();
+                while !inner.finished {
+                    inner = self.packet.cond.wait(inner).unwrap();
+                }
+            }
+        }
+
+        let mut inner = self.packet.inner.lock().unwrap();
+        inner.result.take().expect("Coroutine finished without result")
+    }
+
+    /// Abort the coroutine
+    ///
+    /// The coroutine is waked up if it is blocked in an I/O operation, sleep or receiving
+    /// from a channel. Its next I/O operation fails with an `Interrupted` error, and the next
+    /// `sched`, sleep or channel receive unwinds it with an `Aborted` panic, which is what
+    /// `join` returns if it unwinds to the end.
+    pub fn abort(&self) {
+        self.cancel.cancel();
+    }
+
+    /// Whether `abort` has been called
+    pub fn is_aborted(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::time::Duration;
+
+    use scheduler::Scheduler;
+    use runtime::{Runtime, Config};
+    use net::tcp::TcpListener;
+    use sync::mpsc::channel;
+
+    use super::Aborted;
+
+    #[test]
+    fn test_join_handle() {
+        let runtime = Runtime::new(Config::new().threads(2));
+
+        let hdl = runtime.spawn(|| {
+            let value = Scheduler::spawn(|| 1 + 1).join().unwrap();
+            let panicked = Scheduler::spawn(|| {
+                panic!("Panicked inside coroutine");
+            }).join();
+
+            (value, panicked.is_err())
+        });
+
+        runtime.run();
+
+        assert_eq!(hdl.join().unwrap(), (2, true));
+    }
+
+    #[test]
+    fn test_abort() {
+        let runtime = Runtime::new(Config::new());
+
+        let hdl = runtime.spawn(|| {
+            // Every spawned coroutine runs until it is blocked
+            let sleeper = Scheduler::spawn(|| Scheduler::sleep(Duration::from_secs(60)));
+            sleeper.abort();
+            let sleep_aborted = sleeper.join().unwrap_err().is::<Aborted>();
+
+            let (tx, rx) = channel::<i32>();
+            let receiver = Scheduler::spawn(move|| rx.recv());
+            receiver.abort();
+            let recv_aborted = receiver.join().unwrap_err().is::<Aborted>();
+            drop(tx);
+
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let acceptor = Scheduler::spawn(move|| listener.accept().err().map(|e| e.kind()));
+            acceptor.abort();
+            let accept_err = acceptor.join().unwrap();
+
+            (sleep_aborted, recv_aborted, accept_err)
+        });
+
+        runtime.run();
+
+        assert_eq!(hdl.join().unwrap(), (true, true, Some(io::ErrorKind::Interrupted)));
+    }
+}
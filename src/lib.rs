@@ -21,8 +21,15 @@
 
 //! The most naive coroutine scheduler with asynchronous I/O support.
 //!
-//! The scheduler will hold a global lock-free queue for tasks, all worker threads
-//! will take tasks from the global queue.
+//! Every worker thread owns a local lock-free queue for tasks and steals from the
+//! others when it runs out of tasks. The global queue is only used for overflow and
+//! tasks coming from outside of the worker threads.
+//!
+//! Each `Runtime` owns its own scheduler, the free functions and `Scheduler::run` use
+//! a global one.
+//!
+//! Since 0.2, `Scheduler::get` returns an `Arc<Scheduler>` instead of a `&'static
+//! Scheduler`, because the scheduler of a `Runtime` is dropped with it.
 
 #![feature(libc, rt, box_raw, reflect_marker)]
 
@@ -38,34 +45,77 @@ extern crate openssl;
 extern crate bytes;
 
 pub use scheduler::Scheduler;
-pub use options::Options;
+pub use options::{Options, Priority};
+pub use join_handle::JoinHandle;
+pub use runtime::{Runtime, Config};
+pub use coroutine::CoroutineInfo;
+pub use generator::{Generator, Yielder};
+
+use std::thread;
+use std::time::{Duration, Instant};
 
+#[macro_use]
+pub mod coroutine_local;
 pub mod scheduler;
 pub mod net;
 pub mod processor;
 pub mod options;
 pub mod sync;
+pub mod join_handle;
+pub mod runtime;
+pub mod stats;
+pub mod dump;
+pub mod stack_pool;
+pub mod generator;
 mod coroutine;
+mod stack_guard;
+mod blocking;
+mod affinity;
 
 /// Spawn a new Coroutine
-pub fn spawn<F>(f: F)
-    where F: FnOnce() + Send + 'static
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+    where F: FnOnce() -> T + Send + 'static,
+          T: Send + 'static
 {
     Scheduler::spawn(f)
 }
 
 /// Spawn a new Coroutine with options
-pub fn spawn_opts<F>(f: F, opts: Options)
-    where F: FnOnce() + Send + 'static
+pub fn spawn_opts<F, T>(f: F, opts: Options) -> JoinHandle<T>
+    where F: FnOnce() -> T + Send + 'static,
+          T: Send + 'static
 {
     Scheduler::spawn_opts(f, opts)
 }
 
+/// Get the information of the current coroutine
+pub fn current() -> CoroutineInfo {
+    Scheduler::current()
+}
+
+/// Run the blocking `f` in a separate thread pool and wait for its result
+pub fn spawn_blocking<F, T>(f: F) -> thread::Result<T>
+    where F: FnOnce() -> T + Send + 'static,
+          T: Send + 'static
+{
+    Scheduler::spawn_blocking(f)
+}
+
 /// Giveup the CPU
 pub fn sched() {
     Scheduler::sched()
 }
 
+/// Put the current coroutine to sleep for `dur`
+pub fn sleep(dur: Duration) {
+    Scheduler::sleep(dur)
+}
+
+/// Put the current coroutine to sleep until `deadline`
+pub fn sleep_until(deadline: Instant) {
+    Scheduler::sleep_until(deadline)
+}
+
 pub struct Builder {
     opts: Options
 }
@@ -87,8 +137,26 @@ impl Builder {
         self
     }
 
-    pub fn spawn<F>(self, f: F)
-        where F: FnOnce() + Send + 'static
+    /// Always resume the coroutine on the processor which runs it first
+    pub fn pinned(mut self, pinned: bool) -> Builder {
+        self.opts.pinned = pinned;
+        self
+    }
+
+    /// Always resume the coroutine on the processor `processor_id`
+    pub fn pin_to(mut self, processor_id: usize) -> Builder {
+        self.opts.processor = Some(processor_id);
+        self
+    }
+
+    pub fn priority(mut self, priority: Priority) -> Builder {
+        self.opts.priority = priority;
+        self
+    }
+
+    pub fn spawn<F, T>(self, f: F) -> JoinHandle<T>
+        where F: FnOnce() -> T + Send + 'static,
+              T: Send + 'static
     {
         Scheduler::spawn_opts(f, self.opts)
     }
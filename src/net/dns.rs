@@ -0,0 +1,707 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! DNS resolution without blocking the processors
+//!
+//! Host names are looked up in `/etc/hosts` first, then queried from the name servers in
+//! `/etc/resolv.conf` over `net::UdpSocket`, so the current coroutine is blocked instead of
+//! the whole processor. The answers are cached until their TTLs expire.
+//!
+//! Like the resolver of libc, names with less than `ndots` dots are tried with the domains
+//! of the `search` list first, the other names are tried as they are first.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::{self, SocketAddr, SocketAddrV4, SocketAddrV6, Ipv4Addr, Ipv6Addr};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use mio::EventSet;
+
+use bytes::{MutBuf, MutSliceBuf};
+
+use processor::Processor;
+use net::UdpSocket;
+
+const DNS_PORT: u16 = 53;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+const RCODE_NXDOMAIN: u16 = 3;
+
+// Answers are not kept longer than this, even if the TTL says so
+const MAX_TTL: u32 = 86400;
+
+// Same limit as the resolver of glibc
+const MAX_NDOTS: usize = 15;
+
+static NEXT_QUERY_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+lazy_static! {
+    static ref RESOLVER: Resolver = Resolver::system();
+}
+
+/// Like `std::net::ToSocketAddrs`, but host names are resolved by the `Resolver` of this
+/// library, which blocks the current coroutine instead of the processor
+pub trait ToSocketAddrs {
+    fn to_socket_addrs(&self) -> io::Result<Vec<SocketAddr>>;
+}
+
+impl ToSocketAddrs for SocketAddr {
+    fn to_socket_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        Ok(vec![*self])
+    }
+}
+
+impl ToSocketAddrs for SocketAddrV4 {
+    fn to_socket_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        Ok(vec![SocketAddr::V4(*self)])
+    }
+}
+
+impl ToSocketAddrs for SocketAddrV6 {
+    fn to_socket_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        Ok(vec![SocketAddr::V6(*self)])
+    }
+}
+
+impl ToSocketAddrs for (Ipv4Addr, u16) {
+    fn to_socket_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        Ok(vec![SocketAddr::V4(SocketAddrV4::new(self.0, self.1))])
+    }
+}
+
+impl ToSocketAddrs for (Ipv6Addr, u16) {
+    fn to_socket_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        Ok(vec![SocketAddr::V6(SocketAddrV6::new(self.0, self.1, 0, 0))])
+    }
+}
+
+impl<'a> ToSocketAddrs for (&'a str, u16) {
+    fn to_socket_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        let (host, port) = *self;
+        lookup_host(host, port)
+    }
+}
+
+impl ToSocketAddrs for str {
+    fn to_socket_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        if let Ok(addr) = self.parse() {
+            return Ok(vec![addr]);
+        }
+
+        let mut parts = self.rsplitn(2, ':');
+        let port = parts.next().and_then(|p| p.parse().ok());
+        match (parts.next(), port) {
+            (Some(host), Some(port)) => lookup_host(host, port),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid socket address")),
+        }
+    }
+}
+
+impl ToSocketAddrs for String {
+    fn to_socket_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        (&self[..]).to_socket_addrs()
+    }
+}
+
+impl<'a, T: ToSocketAddrs + ?Sized> ToSocketAddrs for &'a T {
+    fn to_socket_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        (**self).to_socket_addrs()
+    }
+}
+
+/// Resolve `host` with the system resolver, the addresses are returned with `port`
+pub fn lookup_host(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    RESOLVER.lookup(host, port)
+}
+
+/// Asynchronous DNS resolver
+pub struct Resolver {
+    nameservers: Vec<SocketAddr>,
+    timeout: Duration,
+    attempts: usize,
+    search: Vec<String>,
+    ndots: usize,
+    hosts: HashMap<String, Vec<SocketAddr>>,
+    cache: Mutex<HashMap<String, (Vec<SocketAddr>, Instant)>>,
+}
+
+impl Resolver {
+    /// Create a resolver which queries `nameservers`, without any hosts entry
+    pub fn new(nameservers: Vec<SocketAddr>) -> Resolver {
+        Resolver {
+            nameservers: nameservers,
+            timeout: Duration::from_secs(5),
+            attempts: 2,
+            search: Vec::new(),
+            ndots: 1,
+            hosts: HashMap::new(),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Create a resolver from `/etc/resolv.conf` and `/etc/hosts`
+    pub fn system() -> Resolver {
+        let mut resolver = Resolver::new(Vec::new());
+
+        match read_file("/etc/resolv.conf") {
+            Ok(conf) => resolver.parse_resolv_conf(&conf),
+            Err(err) => debug!("Failed to read /etc/resolv.conf: {:?}", err),
+        }
+        if resolver.nameservers.is_empty() {
+            let local = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), DNS_PORT);
+            resolver.nameservers.push(SocketAddr::V4(local));
+        }
+
+        match read_file("/etc/hosts") {
+            Ok(hosts) => resolver.parse_hosts(&hosts),
+            Err(err) => debug!("Failed to read /etc/hosts: {:?}", err),
+        }
+        if !resolver.hosts.contains_key("localhost") {
+            let local = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 0);
+            resolver.add_host("localhost", SocketAddr::V4(local));
+        }
+
+        resolver
+    }
+
+    /// Time to wait for the answer of a name server
+    pub fn timeout(mut self, timeout: Duration) -> Resolver {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Number of times to query every name server
+    pub fn attempts(mut self, attempts: usize) -> Resolver {
+        self.attempts = attempts;
+        self
+    }
+
+    /// Domains to try with the names having less than `ndots` dots
+    pub fn search(mut self, domains: Vec<String>) -> Resolver {
+        self.search = domains.iter().map(|d| d.trim_matches('.').to_lowercase()).collect();
+        self
+    }
+
+    /// Minimum number of dots in a name to try it as an absolute name first
+    pub fn ndots(mut self, ndots: usize) -> Resolver {
+        self.ndots = cmp::min(ndots, MAX_NDOTS);
+        self
+    }
+
+    /// Add an entry like the ones in `/etc/hosts`, which won't be queried
+    pub fn host(mut self, name: &str, addr: SocketAddr) -> Resolver {
+        self.add_host(name, addr);
+        self
+    }
+
+    fn add_host(&mut self, name: &str, addr: SocketAddr) {
+        self.hosts.entry(name.to_lowercase()).or_insert(Vec::new()).push(addr);
+    }
+
+    fn parse_resolv_conf(&mut self, conf: &str) {
+        for line in conf.lines() {
+            let mut words = line.split_whitespace();
+
+            match words.next() {
+                Some("nameserver") => {
+                    match words.next().and_then(parse_ip) {
+                        Some(addr) => self.nameservers.push(with_port(addr, DNS_PORT)),
+                        None => debug!("Ignored a name server in resolv.conf: {:?}", line),
+                    }
+                },
+                // The last one of `search` and `domain` wins
+                Some("search") => {
+                    self.search = words.map(|d| d.trim_matches('.').to_lowercase()).collect();
+                },
+                Some("domain") => {
+                    self.search = words.next().map(|d| d.trim_matches('.').to_lowercase())
+                                       .into_iter().collect();
+                },
+                Some("options") => {
+                    for opt in words {
+                        let mut kv = opt.splitn(2, ':');
+                        match (kv.next(), kv.next().and_then(|v| v.parse().ok())) {
+                            (Some("timeout"), Some(secs)) => self.timeout = Duration::from_secs(secs),
+                            (Some("attempts"), Some(n)) => self.attempts = n as usize,
+                            (Some("ndots"), Some(n)) => self.ndots = cmp::min(n as usize, MAX_NDOTS),
+                            _ => {},
+                        }
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+
+    fn parse_hosts(&mut self, hosts: &str) {
+        for line in hosts.lines() {
+            let line = match line.find('#') {
+                Some(pos) => &line[..pos],
+                None => line,
+            };
+
+            let mut words = line.split_whitespace();
+            let addr = match words.next() {
+                Some(w) => match parse_ip(w) {
+                    Some(addr) => addr,
+                    None => continue,
+                },
+                None => continue,
+            };
+
+            for name in words {
+                self.add_host(name, addr);
+            }
+        }
+    }
+
+    /// Resolve `host`, the addresses are returned with `port`
+    ///
+    /// Outside of any coroutine it falls back to the blocking resolver of the system.
+    pub fn lookup(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        if let Some(addr) = parse_ip(host) {
+            return Ok(vec![with_port(addr, port)]);
+        }
+
+        let name = host.trim_right_matches('.').to_lowercase();
+        if let Some(addrs) = self.hosts.get(&name) {
+            return Ok(addrs.iter().map(|a| with_port(*a, port)).collect());
+        }
+
+        let candidates = self.candidates(&name, host.ends_with('.'));
+        for fqdn in candidates.iter() {
+            if let Some(&(ref addrs, expire)) = self.cache.lock().unwrap().get(fqdn) {
+                if expire > Instant::now() {
+                    return Ok(addrs.iter().map(|a| with_port(*a, port)).collect());
+                }
+            }
+        }
+
+        if Processor::try_current().and_then(|p| p.running()).is_none() {
+            let addrs = try!(net::ToSocketAddrs::to_socket_addrs(&(host, port)));
+            return Ok(addrs.collect());
+        }
+
+        let mut last_err = io::Error::new(io::ErrorKind::NotFound, "host not found");
+        for fqdn in candidates {
+            match self.resolve(&fqdn) {
+                Ok(addrs) => return Ok(addrs.into_iter().map(|a| with_port(a, port)).collect()),
+                Err(err) => {
+                    debug!("Failed to resolve {:?}: {:?}", fqdn, err);
+                    // Keep the reason why a name existing in DNS can't be used
+                    if err.kind() != io::ErrorKind::NotFound
+                            || last_err.kind() == io::ErrorKind::NotFound {
+                        last_err = err;
+                    }
+                }
+            }
+        }
+
+        Err(last_err)
+    }
+
+    /// Names to query for `name` in order, `absolute` if it was ended with a dot
+    fn candidates(&self, name: &str, absolute: bool) -> Vec<String> {
+        if absolute || self.search.is_empty() {
+            return vec![name.to_owned()];
+        }
+
+        let mut names: Vec<String> = self.search.iter()
+                                                .filter(|d| !d.is_empty())
+                                                .map(|d| format!("{}.{}", name, d))
+                                                .collect();
+        if name.matches('.').count() >= self.ndots {
+            names.insert(0, name.to_owned());
+        } else {
+            names.push(name.to_owned());
+        }
+        names
+    }
+
+    /// Query the A and AAAA records of the fully qualified `name` and cache them
+    fn resolve(&self, name: &str) -> io::Result<Vec<SocketAddr>> {
+        let (mut addrs, mut ttl) = try!(self.query(name, TYPE_A));
+
+        // Some name servers fail AAAA queries, that only means there isn't any v6 address
+        match self.query(name, TYPE_AAAA) {
+            Ok((addrs6, ttl_aaaa)) => {
+                addrs.extend(addrs6);
+                ttl = cmp::min(ttl, ttl_aaaa);
+            },
+            Err(err) => debug!("Failed to query the AAAA records of {:?}: {:?}", name, err),
+        }
+
+        if addrs.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::Other, "host has no address"));
+        }
+
+        let expire = Instant::now() + Duration::from_secs(ttl as u64);
+        self.cache.lock().unwrap().insert(name.to_owned(), (addrs.clone(), expire));
+        Ok(addrs)
+    }
+
+    /// Query the name servers for records of `qtype`, returns the addresses with the
+    /// smallest TTL of them
+    fn query(&self, name: &str, qtype: u16) -> io::Result<(Vec<SocketAddr>, u32)> {
+        let id = query_id();
+        let packet = try!(build_query(id, name, qtype));
+
+        let mut last_err = io::Error::new(io::ErrorKind::Other, "no name server is configured");
+        for _ in 0..self.attempts {
+            for ns in self.nameservers.iter() {
+                match self.query_server(ns, id, &packet, qtype) {
+                    Ok(answer) => return Ok(answer),
+                    Err(err) => {
+                        debug!("Failed to query {:?} from {}: {:?}", name, ns, err);
+                        if err.kind() == io::ErrorKind::NotFound {
+                            return Err(err);
+                        }
+                        last_err = err;
+                    }
+                }
+            }
+        }
+
+        Err(last_err)
+    }
+
+    fn query_server(&self, ns: &SocketAddr, id: u16, packet: &[u8], qtype: u16)
+            -> io::Result<(Vec<SocketAddr>, u32)> {
+        let local = match *ns {
+            SocketAddr::V4(..) => SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0)),
+            SocketAddr::V6(..) => SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), 0, 0, 0)),
+        };
+        let sock = try!(UdpSocket::bind(&local));
+        try!(sock.send_to(packet, ns));
+
+        let deadline = Instant::now() + self.timeout;
+        let mut buf = [0u8; 512];
+
+        loop {
+            let len = {
+                let total = buf.len();
+                let mut mbuf = MutSliceBuf::wrap(&mut buf);
+                match try!((*sock).recv_from(&mut mbuf)) {
+                    Some(from) if from == *ns => Some(total - mbuf.remaining()),
+                    Some(from) => {
+                        debug!("Ignored DNS response from {}", from);
+                        None
+                    },
+                    None => None,
+                }
+            };
+
+            if let Some(len) = len {
+                match parse_response(&buf[..len], id, qtype) {
+                    // A response to another query
+                    Ok(None) => {},
+                    Ok(Some(answer)) => return answer,
+                    Err(err) => return Err(err),
+                }
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "DNS query timed out"));
+            }
+            try!(Processor::current().wait_event_timeout(&*sock, EventSet::readable(),
+                                                         Some(deadline - now)));
+        }
+    }
+}
+
+fn read_file(path: &str) -> io::Result<String> {
+    let mut content = String::new();
+    try!(try!(File::open(path)).read_to_string(&mut content));
+    Ok(content)
+}
+
+fn parse_ip(s: &str) -> Option<SocketAddr> {
+    if let Ok(ip) = s.parse() {
+        return Some(SocketAddr::V4(SocketAddrV4::new(ip, 0)));
+    }
+    s.parse().ok().map(|ip| SocketAddr::V6(SocketAddrV6::new(ip, 0, 0, 0)))
+}
+
+fn with_port(addr: SocketAddr, port: u16) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(a) => SocketAddr::V4(SocketAddrV4::new(*a.ip(), port)),
+        SocketAddr::V6(a) => SocketAddr::V6(SocketAddrV6::new(*a.ip(), port, a.flowinfo(), a.scope_id())),
+    }
+}
+
+fn query_id() -> u16 {
+    let nanos = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(dur) => dur.subsec_nanos() as usize,
+        Err(..) => 0,
+    };
+    (nanos ^ NEXT_QUERY_ID.fetch_add(1, Ordering::Relaxed).wrapping_mul(0x9e37)) as u16
+}
+
+fn invalid_response() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "malformed DNS response")
+}
+
+fn push_u16(buf: &mut Vec<u8>, v: u16) {
+    buf.push((v >> 8) as u8);
+    buf.push(v as u8);
+}
+
+fn read_u16(buf: &[u8], pos: usize) -> io::Result<u16> {
+    if pos + 2 > buf.len() {
+        return Err(invalid_response());
+    }
+    Ok(((buf[pos] as u16) << 8) | buf[pos + 1] as u16)
+}
+
+fn read_u32(buf: &[u8], pos: usize) -> io::Result<u32> {
+    let hi = try!(read_u16(buf, pos)) as u32;
+    let lo = try!(read_u16(buf, pos + 2)) as u32;
+    Ok((hi << 16) | lo)
+}
+
+fn build_query(id: u16, name: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    if name.is_empty() || name.len() > 253 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid host name"));
+    }
+
+    let mut packet = Vec::with_capacity(name.len() + 18);
+    push_u16(&mut packet, id);
+    // Standard query, recursion desired
+    push_u16(&mut packet, 0x0100);
+    push_u16(&mut packet, 1);
+    push_u16(&mut packet, 0);
+    push_u16(&mut packet, 0);
+    push_u16(&mut packet, 0);
+
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid host name"));
+        }
+        packet.push(label.len() as u8);
+        packet.extend(label.bytes());
+    }
+    packet.push(0);
+
+    push_u16(&mut packet, qtype);
+    push_u16(&mut packet, CLASS_IN);
+    Ok(packet)
+}
+
+/// Position after the name starting at `pos`
+fn skip_name(buf: &[u8], mut pos: usize) -> io::Result<usize> {
+    loop {
+        if pos >= buf.len() {
+            return Err(invalid_response());
+        }
+
+        let len = buf[pos] as usize;
+        if len == 0 {
+            return Ok(pos + 1);
+        } else if len & 0xc0 == 0xc0 {
+            // Compressed, pointing to a previous name
+            return Ok(pos + 2);
+        }
+        pos += len + 1;
+    }
+}
+
+/// Returns `None` if it is not the response of the query `id`
+fn parse_response(buf: &[u8], id: u16, qtype: u16)
+        -> io::Result<Option<io::Result<(Vec<SocketAddr>, u32)>>> {
+    if try!(read_u16(buf, 0)) != id {
+        return Ok(None);
+    }
+
+    let flags = try!(read_u16(buf, 2));
+    if flags & 0x8000 == 0 {
+        return Ok(None);
+    }
+
+    match flags & 0xf {
+        0 => {},
+        RCODE_NXDOMAIN => {
+            return Ok(Some(Err(io::Error::new(io::ErrorKind::NotFound, "host not found"))));
+        },
+        rcode => {
+            let msg = format!("name server failed with code {}", rcode);
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+    }
+
+    let questions = try!(read_u16(buf, 4));
+    let answers = try!(read_u16(buf, 6));
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = try!(skip_name(buf, pos)) + 4;
+    }
+
+    let mut addrs = Vec::new();
+    let mut ttl = MAX_TTL;
+    for _ in 0..answers {
+        pos = try!(skip_name(buf, pos));
+        let rtype = try!(read_u16(buf, pos));
+        let class = try!(read_u16(buf, pos + 2));
+        let rttl = try!(read_u32(buf, pos + 4));
+        let len = try!(read_u16(buf, pos + 8)) as usize;
+        pos += 10;
+
+        if pos + len > buf.len() {
+            return Err(invalid_response());
+        }
+        let data = &buf[pos..pos + len];
+        pos += len;
+
+        // CNAMEs are skipped, the name servers give the records of the target too
+        if class != CLASS_IN || rtype != qtype {
+            continue;
+        }
+
+        let addr = match (rtype, len) {
+            (TYPE_A, 4) => {
+                SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(data[0], data[1], data[2], data[3]), 0))
+            },
+            (TYPE_AAAA, 16) => {
+                let mut segs = [0u16; 8];
+                for i in 0..8 {
+                    segs[i] = ((data[i * 2] as u16) << 8) | data[i * 2 + 1] as u16;
+                }
+                let ip = Ipv6Addr::new(segs[0], segs[1], segs[2], segs[3],
+                                       segs[4], segs[5], segs[6], segs[7]);
+                SocketAddr::V6(SocketAddrV6::new(ip, 0, 0, 0))
+            },
+            _ => return Err(invalid_response()),
+        };
+
+        if rttl < ttl {
+            ttl = rttl;
+        }
+        addrs.push(addr);
+    }
+
+    Ok(Some(Ok((addrs, ttl))))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::net::{SocketAddr, SocketAddrV4, Ipv4Addr};
+    use std::time::Duration;
+
+    use runtime::{Runtime, Config};
+    use net::UdpSocket;
+
+    use super::{Resolver, DNS_PORT};
+
+    fn v4(a: u8, b: u8, c: u8, d: u8, port: u16) -> SocketAddr {
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(a, b, c, d), port))
+    }
+
+    // Answers `example.test` with 10.0.0.7, and nothing else exists. AAAA queries fail
+    fn serve(server: UdpSocket, queries: usize) {
+        let mut buf = [0u8; 512];
+        for _ in 0..queries {
+            let (len, peer) = server.recv_from(&mut buf).unwrap();
+            let question = &buf[12..len];
+            let qtype = buf[len - 3];
+            let found = &question[..14] == b"\x07example\x04test";
+            let rcode = if qtype != 1 { 0x82 } else if found { 0x80 } else { 0x83 };
+
+            let mut resp = vec![buf[0], buf[1], 0x81, rcode, 0, 1];
+            resp.extend([0, if found && qtype == 1 { 1 } else { 0 }, 0, 0, 0, 0].iter().cloned());
+            resp.extend(question.iter().cloned());
+            if found && qtype == 1 {
+                resp.extend([0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 10, 0, 0, 7].iter().cloned());
+            }
+
+            server.send_to(&resp, &peer).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_resolver() {
+        let runtime = Runtime::new(Config::new());
+
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let ns = server.local_addr().unwrap();
+        // A and AAAA of example.test, A of missing.test, then A of example.missing, A and
+        // AAAA of example.test for the search list
+        runtime.spawn(move|| serve(server, 6));
+
+        let hdl = runtime.spawn(move|| {
+            let resolver = Resolver::new(vec![ns])
+                .timeout(Duration::from_millis(500))
+                .attempts(1)
+                .host("myhost", v4(192, 168, 1, 1, 0));
+
+            let first = resolver.lookup("example.test", 80).unwrap();
+            let cached = resolver.lookup("Example.Test.", 8080).unwrap();
+            let missing = resolver.lookup("missing.test", 80).unwrap_err().kind();
+            let host = resolver.lookup("myhost", 22).unwrap();
+
+            let resolver = Resolver::new(vec![ns])
+                .timeout(Duration::from_millis(500))
+                .attempts(1)
+                .search(vec!["missing".to_owned(), "test".to_owned()]);
+            let searched = resolver.lookup("example", 443).unwrap();
+            (first, cached, missing, host, searched)
+        });
+
+        runtime.run();
+
+        let (first, cached, missing, host, searched) = hdl.join().unwrap();
+        assert_eq!(first, vec![v4(10, 0, 0, 7, 80)]);
+        assert_eq!(cached, vec![v4(10, 0, 0, 7, 8080)]);
+        assert_eq!(missing, io::ErrorKind::NotFound);
+        assert_eq!(host, vec![v4(192, 168, 1, 1, 22)]);
+        assert_eq!(searched, vec![v4(10, 0, 0, 7, 443)]);
+    }
+
+    #[test]
+    fn test_parse_conf() {
+        let mut resolver = Resolver::new(Vec::new());
+        resolver.parse_resolv_conf("# comment\nnameserver 10.0.0.1\nnameserver bogus\n\
+                                    domain old.com\nsearch example.com corp.\n\
+                                    options timeout:1 attempts:3 ndots:2\n");
+        resolver.parse_hosts("127.0.0.1 localhost\n10.1.1.1  db  db.local # database\n#10.2.2.2 off\n");
+
+        assert_eq!(resolver.nameservers, vec![v4(10, 0, 0, 1, DNS_PORT)]);
+        assert_eq!(resolver.timeout, Duration::from_secs(1));
+        assert_eq!(resolver.attempts, 3);
+        assert_eq!(resolver.search, vec!["example.com".to_owned(), "corp".to_owned()]);
+        assert_eq!(resolver.ndots, 2);
+        assert_eq!(resolver.candidates("db.x", false),
+                   vec!["db.x.example.com".to_owned(), "db.x.corp".to_owned(), "db.x".to_owned()]);
+        assert_eq!(resolver.candidates("a.b.c", false),
+                   vec!["a.b.c".to_owned(), "a.b.c.example.com".to_owned(), "a.b.c.corp".to_owned()]);
+        assert_eq!(resolver.candidates("db.x", true), vec!["db.x".to_owned()]);
+        assert_eq!(resolver.hosts.get("db.local"), Some(&vec![v4(10, 1, 1, 1, 0)]));
+        assert!(!resolver.hosts.contains_key("off"));
+    }
+}
@@ -23,7 +23,7 @@
 #![allow(dead_code)]
 
 use std::io::{self, Read, Write};
-use std::net::{ToSocketAddrs, SocketAddr, Shutdown};
+use std::net::{SocketAddr, Shutdown};
 use std::fmt;
 use std::convert::From;
 
@@ -31,6 +31,7 @@ use hyper;
 use hyper::net::{NetworkListener, NetworkStream, NetworkConnector};
 
 use net::tcp::{TcpStream, TcpListener};
+use net::ToSocketAddrs;
 use net;
 
 pub struct HttpListener(TcpListener);
@@ -48,6 +49,12 @@ impl HttpListener {
         Ok(HttpListener(try!(TcpListener::bind(addr))))
     }
 
+    /// Start `n` listeners on the same address with `SO_REUSEPORT`.
+    pub fn new_reuseport<To: ToSocketAddrs>(addr: To, n: usize) -> io::Result<Vec<HttpListener>> {
+        let listeners = try!(TcpListener::bind_reuseport(addr, n));
+        Ok(listeners.into_iter().map(HttpListener).collect())
+    }
+
 }
 
 impl NetworkListener for HttpListener {
@@ -20,9 +20,12 @@
 //  FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 //  DEALINGS IN THE SOFTWARE.
 
-use std::net::{ToSocketAddrs, SocketAddr};
+use std::net::SocketAddr;
 use std::convert::From;
+use std::sync::Arc;
 use std::io::{self, Write, BufWriter};
+use std::cmp;
+use std::time::Duration;
 
 use hyper;
 use hyper::http;
@@ -35,16 +38,21 @@ use hyper::status::StatusCode;
 use hyper::error::Error;
 
 use net::http::conn::{HttpListener, HttpsListener, Ssl};
+use net::ToSocketAddrs;
 
 use scheduler::Scheduler;
+use options::Options;
 
 /// A server can listen on a TCP socket.
 ///
 /// Once listening, it will create a `Request`/`Response` pair for each
 /// incoming connection, and hand them to the provided handler.
+///
+/// A server with several listeners accepts from each of them in a coroutine pinned to
+/// a different processor.
 #[derive(Debug)]
 pub struct Server<L = HttpListener> {
-    listener: L,
+    listeners: Vec<L>,
 }
 
 impl<L: NetworkListener> Server<L> {
@@ -52,7 +60,7 @@ impl<L: NetworkListener> Server<L> {
     #[inline]
     pub fn new(listener: L) -> Server<L> {
         Server {
-            listener: listener,
+            listeners: vec![listener],
         }
     }
 }
@@ -62,6 +70,17 @@ impl Server<HttpListener> {
     pub fn http<To: ToSocketAddrs>(addr: To) -> hyper::Result<Server<HttpListener>> {
         HttpListener::new(addr).map(Server::new).map_err(From::from)
     }
+
+    /// Creates a new server with `n` listeners bound with `SO_REUSEPORT`, so the kernel
+    /// load-balances the connections among `n` processors.
+    ///
+    /// `n` must not be greater than the number of threads of the scheduler, see `listen`.
+    pub fn http_reuseport<To: ToSocketAddrs>(addr: To, n: usize) -> hyper::Result<Server<HttpListener>> {
+        let listeners = try!(HttpListener::new_reuseport(addr, n));
+        Ok(Server {
+            listeners: listeners,
+        })
+    }
 }
 
 impl<S: Ssl + Clone + Send> Server<HttpsListener<S>> {
@@ -75,25 +94,67 @@ impl<S: Ssl + Clone + Send> Server<HttpsListener<S>> {
 
 impl<L: NetworkListener + Send + 'static> Server<L> {
     /// Binds to a socket.
+    ///
+    /// Fails if there are more listeners than `Scheduler::threads`, since each of them is
+    /// pinned to its own processor. Call it in a coroutine when running the global scheduler,
+    /// so the number of threads given to `Scheduler::run` is known.
     pub fn listen<H: Handler + 'static>(mut self, handler: H) -> hyper::Result<SocketAddr> {
-        let socket = try!(self.listener.local_addr());
+        let threads = Scheduler::get().threads();
+        if self.listeners.len() > threads {
+            let msg = format!("{} listeners but only {} threads", self.listeners.len(), threads);
+            return Err(From::from(io::Error::new(io::ErrorKind::InvalidInput, msg)));
+        }
 
-        Scheduler::spawn(move|| {
-            use std::sync::Arc;
+        let socket = try!(self.listeners[0].local_addr());
 
-            let handler = Arc::new(handler);
-            loop {
-                let mut stream = self.listener.accept().unwrap();
+        let handler = Arc::new(handler);
+        let pinned = self.listeners.len() > 1;
+        for (id, listener) in self.listeners.into_iter().enumerate() {
+            let handler = handler.clone();
+            let accept = move|| accept_loop(listener, handler);
 
-                let handler = handler.clone();
-                Scheduler::spawn(move|| Worker(&*handler).handle_connection(&mut stream));
+            if pinned {
+                Scheduler::spawn_opts(accept, Options::new().pin_to(id));
+            } else {
+                Scheduler::spawn(accept);
             }
-        });
+        }
 
         Ok(socket)
     }
 }
 
+// Delay before retrying a failed accept, doubled on every consecutive failure
+const MIN_ACCEPT_BACKOFF_MS: u64 = 10;
+const MAX_ACCEPT_BACKOFF_MS: u64 = 1_000;
+
+fn accept_loop<L, H>(mut listener: L, handler: Arc<H>)
+    where L: NetworkListener + Send + 'static,
+          H: Handler + 'static
+{
+    let mut backoff = MIN_ACCEPT_BACKOFF_MS;
+    loop {
+        let mut stream = match listener.accept() {
+            Ok(stream) => stream,
+            Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::Interrupted => {
+                debug!("Scheduler is shutting down, stop accepting");
+                break;
+            },
+            Err(e) => {
+                // Errors like `EMFILE` won't go away by retrying immediately
+                error!("Failed to accept: {}, retrying in {} ms", e, backoff);
+                Scheduler::sleep(Duration::from_millis(backoff));
+                backoff = cmp::min(backoff * 2, MAX_ACCEPT_BACKOFF_MS);
+                continue;
+            }
+        };
+        backoff = MIN_ACCEPT_BACKOFF_MS;
+
+        let handler = handler.clone();
+        Scheduler::spawn(move|| Worker(&*handler).handle_connection(&mut stream));
+    }
+}
+
 struct Worker<'a, H: Handler + 'static>(&'a H);
 
 impl<'a, H: Handler + 'static> Worker<'a, H> {
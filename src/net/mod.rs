@@ -23,13 +23,19 @@
 
 pub use self::tcp::{TcpListener, TcpStream, TcpSocket, Shutdown};
 pub use self::udp::UdpSocket;
+pub use self::dns::{ToSocketAddrs, Resolver, lookup_host};
+pub use self::unix::{UnixListener, UnixStream, UnixDatagram};
 
 use std::io;
-use std::net::{ToSocketAddrs, SocketAddr};
+use std::net::SocketAddr;
 
 pub mod tcp;
 pub mod udp;
 pub mod http;
+pub mod dns;
+pub mod unix;
+mod sockopt;
+mod sys;
 
 fn each_addr<A: ToSocketAddrs, F, T>(addr: A, mut f: F) -> io::Result<T>
     where F: FnMut(&SocketAddr) -> io::Result<T>
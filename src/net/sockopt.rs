@@ -0,0 +1,176 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Socket options which are not exposed by mio
+
+use std::i32;
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+use libc::{c_int, c_void};
+
+use net::sys::*;
+
+const IPPROTO_IP: c_int = 0;
+const IPPROTO_TCP: c_int = 6;
+const IPPROTO_IPV6: c_int = 41;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct linger {
+    l_onoff: c_int,
+    l_linger: c_int,
+}
+
+extern {
+    fn setsockopt(fd: c_int, level: c_int, name: c_int, val: *const c_void, len: u32) -> c_int;
+    fn getsockopt(fd: c_int, level: c_int, name: c_int, val: *mut c_void, len: *mut u32) -> c_int;
+}
+
+fn set<T: Copy>(fd: RawFd, level: c_int, name: c_int, val: T) -> io::Result<()> {
+    let ret = unsafe {
+        setsockopt(fd, level, name, &val as *const T as *const c_void, mem::size_of::<T>() as u32)
+    };
+
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn get<T: Copy>(fd: RawFd, level: c_int, name: c_int) -> io::Result<T> {
+    unsafe {
+        let mut val: T = mem::zeroed();
+        let mut len = mem::size_of::<T>() as u32;
+        if getsockopt(fd, level, name, &mut val as *mut T as *mut c_void, &mut len) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(val)
+    }
+}
+
+fn to_secs(dur: Duration) -> c_int {
+    // Rounded up, so a short but non-zero duration won't turn into zero
+    let secs = dur.as_secs() + if dur.subsec_nanos() > 0 { 1 } else { 0 };
+    if secs > i32::MAX as u64 { i32::MAX } else { secs as c_int }
+}
+
+pub fn set_nodelay(fd: RawFd, nodelay: bool) -> io::Result<()> {
+    set(fd, IPPROTO_TCP, TCP_NODELAY, nodelay as c_int)
+}
+
+pub fn nodelay(fd: RawFd) -> io::Result<bool> {
+    get::<c_int>(fd, IPPROTO_TCP, TCP_NODELAY).map(|v| v != 0)
+}
+
+fn keepidle() -> io::Result<c_int> {
+    TCP_KEEPIDLE.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::Other, "keepalive idle time is not supported on this platform")
+    })
+}
+
+pub fn set_keepalive(fd: RawFd, idle: Option<Duration>) -> io::Result<()> {
+    match idle {
+        Some(idle) => {
+            let opt = try!(keepidle());
+            try!(set(fd, SOL_SOCKET, SO_KEEPALIVE, 1 as c_int));
+            set(fd, IPPROTO_TCP, opt, to_secs(idle))
+        },
+        None => set(fd, SOL_SOCKET, SO_KEEPALIVE, 0 as c_int),
+    }
+}
+
+pub fn keepalive(fd: RawFd) -> io::Result<Option<Duration>> {
+    if try!(get::<c_int>(fd, SOL_SOCKET, SO_KEEPALIVE)) == 0 {
+        return Ok(None);
+    }
+    let idle = try!(get::<c_int>(fd, IPPROTO_TCP, try!(keepidle())));
+    Ok(Some(Duration::from_secs(idle as u64)))
+}
+
+pub fn set_linger(fd: RawFd, dur: Option<Duration>) -> io::Result<()> {
+    set(fd, SOL_SOCKET, SO_LINGER, linger {
+        l_onoff: dur.is_some() as c_int,
+        l_linger: dur.map(to_secs).unwrap_or(0),
+    })
+}
+
+pub fn linger(fd: RawFd) -> io::Result<Option<Duration>> {
+    let val = try!(get::<linger>(fd, SOL_SOCKET, SO_LINGER));
+    if val.l_onoff == 0 {
+        Ok(None)
+    } else {
+        Ok(Some(Duration::from_secs(val.l_linger as u64)))
+    }
+}
+
+/// `v6` selects the IPv6 hop limit instead of the IPv4 TTL
+pub fn set_ttl(fd: RawFd, v6: bool, ttl: u32) -> io::Result<()> {
+    if v6 {
+        set(fd, IPPROTO_IPV6, IPV6_UNICAST_HOPS, ttl as c_int)
+    } else {
+        set(fd, IPPROTO_IP, IP_TTL, ttl as c_int)
+    }
+}
+
+pub fn ttl(fd: RawFd, v6: bool) -> io::Result<u32> {
+    let ttl = if v6 {
+        try!(get::<c_int>(fd, IPPROTO_IPV6, IPV6_UNICAST_HOPS))
+    } else {
+        try!(get::<c_int>(fd, IPPROTO_IP, IP_TTL))
+    };
+    Ok(ttl as u32)
+}
+
+pub fn set_send_buffer_size(fd: RawFd, size: usize) -> io::Result<()> {
+    set(fd, SOL_SOCKET, SO_SNDBUF, size as c_int)
+}
+
+pub fn send_buffer_size(fd: RawFd) -> io::Result<usize> {
+    get::<c_int>(fd, SOL_SOCKET, SO_SNDBUF).map(|v| v as usize)
+}
+
+pub fn set_recv_buffer_size(fd: RawFd, size: usize) -> io::Result<()> {
+    set(fd, SOL_SOCKET, SO_RCVBUF, size as c_int)
+}
+
+pub fn recv_buffer_size(fd: RawFd) -> io::Result<usize> {
+    get::<c_int>(fd, SOL_SOCKET, SO_RCVBUF).map(|v| v as usize)
+}
+
+pub fn set_reuseaddr(fd: RawFd, reuse: bool) -> io::Result<()> {
+    set(fd, SOL_SOCKET, SO_REUSEADDR, reuse as c_int)
+}
+
+pub fn reuseaddr(fd: RawFd) -> io::Result<bool> {
+    get::<c_int>(fd, SOL_SOCKET, SO_REUSEADDR).map(|v| v != 0)
+}
+
+pub fn set_reuseport(fd: RawFd, reuse: bool) -> io::Result<()> {
+    set(fd, SOL_SOCKET, SO_REUSEPORT, reuse as c_int)
+}
+
+pub fn reuseport(fd: RawFd) -> io::Result<bool> {
+    get::<c_int>(fd, SOL_SOCKET, SO_REUSEPORT).map(|v| v != 0)
+}
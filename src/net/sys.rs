@@ -0,0 +1,83 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+
+//! Socket constants which differ between the platforms, shared by `unix` and `sockopt`
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod os {
+    use libc::c_int;
+
+    pub const SOL_SOCKET: c_int = 1;
+    pub const SO_REUSEADDR: c_int = 2;
+    pub const SO_REUSEPORT: c_int = 15;
+    pub const SO_KEEPALIVE: c_int = 9;
+    pub const SO_LINGER: c_int = 13;
+    pub const SO_SNDBUF: c_int = 7;
+    pub const SO_RCVBUF: c_int = 8;
+    pub const TCP_NODELAY: c_int = 1;
+    pub const TCP_KEEPIDLE: Option<c_int> = Some(4);
+    pub const IP_TTL: c_int = 2;
+    pub const IPV6_UNICAST_HOPS: c_int = 16;
+
+    pub const O_NONBLOCK: c_int = 0o4000;
+    pub const MSG_CTRUNC: c_int = 0x8;
+    pub const MSG_CMSG_CLOEXEC: c_int = 0x40000000;
+}
+
+#[cfg(any(target_os = "macos",
+          target_os = "ios",
+          target_os = "freebsd",
+          target_os = "dragonfly",
+          target_os = "bitrig",
+          target_os = "openbsd"))]
+mod os {
+    use libc::c_int;
+
+    pub const SOL_SOCKET: c_int = 0xffff;
+    pub const SO_REUSEADDR: c_int = 0x4;
+    pub const SO_REUSEPORT: c_int = 0x200;
+    pub const SO_KEEPALIVE: c_int = 0x8;
+    // `SO_LINGER` of Darwin counts in clock ticks, this one counts in seconds
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    pub const SO_LINGER: c_int = 0x1080;
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    pub const SO_LINGER: c_int = 0x80;
+    pub const SO_SNDBUF: c_int = 0x1001;
+    pub const SO_RCVBUF: c_int = 0x1002;
+    pub const TCP_NODELAY: c_int = 0x1;
+    // Named `TCP_KEEPALIVE` on Darwin, OpenBSD only has the system-wide setting
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    pub const TCP_KEEPIDLE: Option<c_int> = Some(0x10);
+    #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+    pub const TCP_KEEPIDLE: Option<c_int> = Some(0x100);
+    #[cfg(any(target_os = "bitrig", target_os = "openbsd"))]
+    pub const TCP_KEEPIDLE: Option<c_int> = None;
+    pub const IP_TTL: c_int = 4;
+    pub const IPV6_UNICAST_HOPS: c_int = 4;
+
+    pub const O_NONBLOCK: c_int = 0x4;
+    pub const MSG_CTRUNC: c_int = 0x20;
+    // Not available everywhere, the descriptors are set close-on-exec afterwards
+    pub const MSG_CMSG_CLOEXEC: c_int = 0;
+}
+
+pub use self::os::*;
@@ -22,14 +22,18 @@
 //! TCP
 
 use std::io;
-use std::net::{ToSocketAddrs, SocketAddr};
+use std::net::SocketAddr;
 use std::ops::{Deref, DerefMut};
 use std::convert::From;
 use std::iter::Iterator;
+use std::time::Duration;
+use std::os::unix::io::AsRawFd;
 
 use mio::{self, EventSet};
 
 use processor::Processor;
+use net::ToSocketAddrs;
+use net::sockopt;
 
 #[derive(Debug)]
 pub struct TcpSocket(::mio::tcp::TcpSocket);
@@ -51,11 +55,45 @@ impl TcpSocket {
                 &SocketAddr::V4(..) => try!(TcpSocket::v4()).0.connect(a),
                 &SocketAddr::V6(..) => try!(TcpSocket::v6()).0.connect(a),
             }
-        }).map(|(stream, complete)| (TcpStream(stream), complete))
+        }).map(|(stream, complete)| (TcpStream::new(stream), complete))
     }
 
     pub fn listen(self, backlog: usize) -> io::Result<TcpListener> {
-        Ok(TcpListener(try!(self.0.listen(backlog))))
+        Ok(TcpListener::new(try!(self.0.listen(backlog))))
+    }
+
+    /// Allow binding to an address in `TIME_WAIT`, must be set before `bind`
+    pub fn set_reuseaddr(&self, reuse: bool) -> io::Result<()> {
+        sockopt::set_reuseaddr(self.0.as_raw_fd(), reuse)
+    }
+
+    pub fn reuseaddr(&self) -> io::Result<bool> {
+        sockopt::reuseaddr(self.0.as_raw_fd())
+    }
+
+    /// Allow several sockets to bind to the same address and port, must be set before `bind`
+    pub fn set_reuseport(&self, reuse: bool) -> io::Result<()> {
+        sockopt::set_reuseport(self.0.as_raw_fd(), reuse)
+    }
+
+    pub fn reuseport(&self) -> io::Result<bool> {
+        sockopt::reuseport(self.0.as_raw_fd())
+    }
+
+    pub fn set_send_buffer_size(&self, size: usize) -> io::Result<()> {
+        sockopt::set_send_buffer_size(self.0.as_raw_fd(), size)
+    }
+
+    pub fn send_buffer_size(&self) -> io::Result<usize> {
+        sockopt::send_buffer_size(self.0.as_raw_fd())
+    }
+
+    pub fn set_recv_buffer_size(&self, size: usize) -> io::Result<()> {
+        sockopt::set_recv_buffer_size(self.0.as_raw_fd(), size)
+    }
+
+    pub fn recv_buffer_size(&self) -> io::Result<usize> {
+        sockopt::recv_buffer_size(self.0.as_raw_fd())
     }
 }
 
@@ -73,21 +111,78 @@ impl DerefMut for TcpSocket {
     }
 }
 
+fn is_v6(addr: io::Result<SocketAddr>) -> io::Result<bool> {
+    match try!(addr) {
+        SocketAddr::V4(..) => Ok(false),
+        SocketAddr::V6(..) => Ok(true),
+    }
+}
+
+fn check_timeout(dur: Option<Duration>) -> io::Result<Option<Duration>> {
+    match dur {
+        Some(ref d) if d.as_secs() == 0 && d.subsec_nanos() == 0 => {
+            Err(io::Error::new(io::ErrorKind::InvalidInput,
+                               "cannot set a 0 duration timeout"))
+        },
+        _ => Ok(dur),
+    }
+}
+
 #[derive(Debug)]
-pub struct TcpListener(::mio::tcp::TcpListener);
+pub struct TcpListener {
+    inner: ::mio::tcp::TcpListener,
+    accept_timeout: Option<Duration>,
+}
 
 impl TcpListener {
+    fn new(inner: ::mio::tcp::TcpListener) -> TcpListener {
+        TcpListener {
+            inner: inner,
+            accept_timeout: None,
+        }
+    }
+
     pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<TcpListener> {
-        super::each_addr(addr, ::mio::tcp::TcpListener::bind).map(TcpListener)
+        super::each_addr(addr, ::mio::tcp::TcpListener::bind).map(TcpListener::new)
+    }
+
+    /// Create `n` listeners bound to the same address with `SO_REUSEPORT`, the kernel
+    /// spreads the incoming connections among them
+    ///
+    /// At least one listener is created. Accepting from each of them in a coroutine pinned
+    /// to a different processor spreads the accepts across the processors.
+    pub fn bind_reuseport<A: ToSocketAddrs>(addr: A, n: usize) -> io::Result<Vec<TcpListener>> {
+        let first = try!(super::each_addr(addr, TcpListener::bind_reuseport_one));
+
+        // The others must use the port which the first one got, in case it was 0
+        let local = try!(first.local_addr());
+        let mut listeners = vec![first];
+        for _ in 1..n {
+            listeners.push(try!(TcpListener::bind_reuseport_one(&local)));
+        }
+
+        Ok(listeners)
+    }
+
+    fn bind_reuseport_one(addr: &SocketAddr) -> io::Result<TcpListener> {
+        let socket = match *addr {
+            SocketAddr::V4(..) => try!(TcpSocket::v4()),
+            SocketAddr::V6(..) => try!(TcpSocket::v6()),
+        };
+
+        try!(socket.set_reuseaddr(true));
+        try!(socket.set_reuseport(true));
+        try!(socket.bind(addr));
+        socket.listen(1024)
     }
 
     pub fn accept(&self) -> io::Result<TcpStream> {
-        match self.0.accept() {
+        match self.inner.accept() {
             Ok(None) => {
                 debug!("accept WouldBlock; going to register into eventloop");
             },
             Ok(Some(stream)) => {
-                return Ok(TcpStream(stream));
+                return Ok(TcpStream::new(stream));
             },
             Err(err) => {
                 return Err(err);
@@ -95,14 +190,15 @@ impl TcpListener {
         }
 
         loop {
-            try!(Processor::current().wait_event(&self.0, EventSet::readable()));
+            try!(Processor::current().wait_event_timeout(&self.inner, EventSet::readable(),
+                                                         self.accept_timeout));
 
-            match self.0.accept() {
+            match self.inner.accept() {
                 Ok(None) => {
                     warn!("accept WouldBlock; Coroutine was awaked by readable event");
                 },
                 Ok(Some(stream)) => {
-                    return Ok(TcpStream(stream));
+                    return Ok(TcpStream::new(stream));
                 },
                 Err(err) => {
                     return Err(err);
@@ -112,25 +208,55 @@ impl TcpListener {
     }
 
     pub fn try_clone(&self) -> io::Result<TcpListener> {
-        Ok(TcpListener(try!(self.0.try_clone())))
+        Ok(TcpListener {
+            inner: try!(self.inner.try_clone()),
+            accept_timeout: self.accept_timeout,
+        })
+    }
+
+    /// Set the timeout for `accept`, which fails with `TimedOut` when no connection arrives in time
+    pub fn set_accept_timeout(&mut self, dur: Option<Duration>) -> io::Result<()> {
+        self.accept_timeout = try!(check_timeout(dur));
+        Ok(())
+    }
+
+    pub fn accept_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(self.accept_timeout)
     }
 
     pub fn incoming<'a>(&'a self) -> Incoming<'a> {
         Incoming(self)
     }
+
+    /// Set the IPv4 TTL, or the IPv6 hop limit, of the accepted connections
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        sockopt::set_ttl(self.inner.as_raw_fd(), try!(is_v6(self.inner.local_addr())), ttl)
+    }
+
+    pub fn ttl(&self) -> io::Result<u32> {
+        sockopt::ttl(self.inner.as_raw_fd(), try!(is_v6(self.inner.local_addr())))
+    }
+
+    pub fn reuseaddr(&self) -> io::Result<bool> {
+        sockopt::reuseaddr(self.inner.as_raw_fd())
+    }
+
+    pub fn reuseport(&self) -> io::Result<bool> {
+        sockopt::reuseport(self.inner.as_raw_fd())
+    }
 }
 
 impl Deref for TcpListener {
     type Target = ::mio::tcp::TcpListener;
 
     fn deref(&self) -> &::mio::tcp::TcpListener {
-        &self.0
+        &self.inner
     }
 }
 
 impl DerefMut for TcpListener {
     fn deref_mut(&mut self) -> &mut ::mio::tcp::TcpListener {
-        &mut self.0
+        &mut self.inner
     }
 }
 
@@ -165,14 +291,36 @@ impl From<Shutdown> for mio::tcp::Shutdown {
 }
 
 #[derive(Debug)]
-pub struct TcpStream(mio::tcp::TcpStream);
+pub struct TcpStream {
+    inner: mio::tcp::TcpStream,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
 
 impl TcpStream {
+    fn new(inner: mio::tcp::TcpStream) -> TcpStream {
+        TcpStream {
+            inner: inner,
+            read_timeout: None,
+            write_timeout: None,
+        }
+    }
+
     pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<TcpStream> {
+        TcpStream::connect_opt(addr, None)
+    }
+
+    /// Open a TCP connection, fails with `TimedOut` if it is not established within `timeout`
+    pub fn connect_timeout<A: ToSocketAddrs>(addr: A, timeout: Duration) -> io::Result<TcpStream> {
+        TcpStream::connect_opt(addr, try!(check_timeout(Some(timeout))))
+    }
+
+    fn connect_opt<A: ToSocketAddrs>(addr: A, timeout: Option<Duration>) -> io::Result<TcpStream> {
         match TcpSocket::connect(addr) {
             Ok((stream, completed)) => {
                 if !completed {
-                    try!(Processor::current().wait_event(&stream.0, EventSet::writable()));
+                    try!(Processor::current().wait_event_timeout(&stream.inner, EventSet::writable(),
+                                                                 timeout));
                     try!(stream.take_socket_error());
                 }
 
@@ -183,25 +331,102 @@ impl TcpStream {
     }
 
     pub fn peer_addr(&self) -> io::Result<SocketAddr> {
-        self.0.peer_addr()
+        self.inner.peer_addr()
     }
 
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
-        self.0.local_addr()
+        self.inner.local_addr()
     }
 
     pub fn try_clone(&self) -> io::Result<TcpStream> {
-        let stream = try!(self.0.try_clone());
+        let stream = try!(self.inner.try_clone());
 
-        Ok(TcpStream(stream))
+        Ok(TcpStream {
+            inner: stream,
+            read_timeout: self.read_timeout,
+            write_timeout: self.write_timeout,
+        })
     }
 
     pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
-        self.0.shutdown(From::from(how))
+        self.inner.shutdown(From::from(how))
     }
 
     pub fn take_socket_error(&self) -> io::Result<()> {
-        self.0.take_socket_error()
+        self.inner.take_socket_error()
+    }
+
+    /// Disable Nagle's algorithm, so small writes are sent immediately
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        sockopt::set_nodelay(self.inner.as_raw_fd(), nodelay)
+    }
+
+    pub fn nodelay(&self) -> io::Result<bool> {
+        sockopt::nodelay(self.inner.as_raw_fd())
+    }
+
+    /// Enable TCP keepalive, probing after the connection has been idle for the duration,
+    /// which is rounded up to seconds
+    pub fn set_keepalive(&self, idle: Option<Duration>) -> io::Result<()> {
+        sockopt::set_keepalive(self.inner.as_raw_fd(), idle)
+    }
+
+    pub fn keepalive(&self) -> io::Result<Option<Duration>> {
+        sockopt::keepalive(self.inner.as_raw_fd())
+    }
+
+    /// Make closing the socket wait for the unsent data for at most the duration
+    pub fn set_linger(&self, dur: Option<Duration>) -> io::Result<()> {
+        sockopt::set_linger(self.inner.as_raw_fd(), dur)
+    }
+
+    pub fn linger(&self) -> io::Result<Option<Duration>> {
+        sockopt::linger(self.inner.as_raw_fd())
+    }
+
+    /// Set the IPv4 TTL, or the IPv6 hop limit
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        sockopt::set_ttl(self.inner.as_raw_fd(), try!(is_v6(self.local_addr())), ttl)
+    }
+
+    pub fn ttl(&self) -> io::Result<u32> {
+        sockopt::ttl(self.inner.as_raw_fd(), try!(is_v6(self.local_addr())))
+    }
+
+    pub fn set_send_buffer_size(&self, size: usize) -> io::Result<()> {
+        sockopt::set_send_buffer_size(self.inner.as_raw_fd(), size)
+    }
+
+    pub fn send_buffer_size(&self) -> io::Result<usize> {
+        sockopt::send_buffer_size(self.inner.as_raw_fd())
+    }
+
+    pub fn set_recv_buffer_size(&self, size: usize) -> io::Result<()> {
+        sockopt::set_recv_buffer_size(self.inner.as_raw_fd(), size)
+    }
+
+    pub fn recv_buffer_size(&self) -> io::Result<usize> {
+        sockopt::recv_buffer_size(self.inner.as_raw_fd())
+    }
+
+    /// Set the timeout for `read`, which fails with `TimedOut` when no data arrives in time
+    pub fn set_read_timeout(&mut self, dur: Option<Duration>) -> io::Result<()> {
+        self.read_timeout = try!(check_timeout(dur));
+        Ok(())
+    }
+
+    /// Set the timeout for `write`, which fails with `TimedOut` when the socket is not writable in time
+    pub fn set_write_timeout(&mut self, dur: Option<Duration>) -> io::Result<()> {
+        self.write_timeout = try!(check_timeout(dur));
+        Ok(())
+    }
+
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(self.read_timeout)
+    }
+
+    pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(self.write_timeout)
     }
 }
 
@@ -209,7 +434,7 @@ impl io::Read for TcpStream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         use mio::TryRead;
 
-        match self.0.try_read(buf) {
+        match self.inner.try_read(buf) {
             Ok(None) => {
                 debug!("TcpStream read WouldBlock");
             },
@@ -224,10 +449,11 @@ impl io::Read for TcpStream {
 
         loop {
             debug!("Read: Going to register event");
-            try!(Processor::current().wait_event(&self.0, EventSet::readable()));
+            try!(Processor::current().wait_event_timeout(&self.inner, EventSet::readable(),
+                                                         self.read_timeout));
             debug!("Read: Got read event");
 
-            match self.0.try_read(buf) {
+            match self.inner.try_read(buf) {
                 Ok(None) => {
                     debug!("TcpStream read WouldBlock");
                 },
@@ -247,7 +473,7 @@ impl io::Write for TcpStream {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         use mio::TryWrite;
 
-        match self.0.try_write(buf) {
+        match self.inner.try_write(buf) {
             Ok(None) => {
                 debug!("TcpStream write WouldBlock");
             },
@@ -262,10 +488,11 @@ impl io::Write for TcpStream {
 
         loop {
             debug!("Write: Going to register event");
-            try!(Processor::current().wait_event(&self.0, EventSet::writable()));
+            try!(Processor::current().wait_event_timeout(&self.inner, EventSet::writable(),
+                                                         self.write_timeout));
             debug!("Write: Got write event");
 
-            match self.0.try_write(buf) {
+            match self.inner.try_write(buf) {
                 Ok(None) => {
                     debug!("TcpStream write WouldBlock");
                 },
@@ -289,12 +516,129 @@ impl Deref for TcpStream {
     type Target = ::mio::tcp::TcpStream;
 
     fn deref(&self) -> &::mio::tcp::TcpStream {
-        &self.0
+        &self.inner
     }
 }
 
 impl DerefMut for TcpStream {
     fn deref_mut(&mut self) -> &mut ::mio::tcp::TcpStream {
-        &mut self.0
+        &mut self.inner
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read};
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use runtime::{Runtime, Config};
+    use scheduler::Scheduler;
+    use processor::Processor;
+    use options::Options;
+
+    use super::{TcpListener, TcpStream, TcpSocket};
+
+    #[test]
+    fn test_tcp_timeouts() {
+        let runtime = Runtime::new(Config::new());
+
+        let hdl = runtime.spawn(|| {
+            let mut listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            listener.set_accept_timeout(Some(Duration::from_millis(100))).unwrap();
+            let accept_err = listener.accept().unwrap_err().kind();
+
+            let mut stream = TcpStream::connect(&addr).unwrap();
+            let _peer = listener.accept().unwrap();
+
+            stream.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+            let mut buf = [0u8; 16];
+            let read_err = stream.read(&mut buf).unwrap_err().kind();
+
+            (accept_err, read_err)
+        });
+
+        runtime.run();
+
+        assert_eq!(hdl.join().unwrap(), (io::ErrorKind::TimedOut, io::ErrorKind::TimedOut));
+    }
+
+    #[test]
+    fn test_tcp_options() {
+        let runtime = Runtime::new(Config::new());
+
+        let hdl = runtime.spawn(|| {
+            let socket = TcpSocket::v4().unwrap();
+            socket.set_reuseaddr(true).unwrap();
+            socket.set_reuseport(true).unwrap();
+            let reuse = (socket.reuseaddr().unwrap(), socket.reuseport().unwrap());
+
+            let local: SocketAddr = "127.0.0.1:0".parse().unwrap();
+            socket.bind(&local).unwrap();
+            let listener = socket.listen(16).unwrap();
+            listener.set_ttl(32).unwrap();
+
+            let stream = TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+            let _peer = listener.accept().unwrap();
+
+            stream.set_nodelay(true).unwrap();
+            stream.set_keepalive(Some(Duration::from_secs(30))).unwrap();
+            stream.set_linger(Some(Duration::from_secs(5))).unwrap();
+            stream.set_ttl(64).unwrap();
+            stream.set_send_buffer_size(0x10000).unwrap();
+            stream.set_recv_buffer_size(0x10000).unwrap();
+            let keepalive = stream.keepalive().unwrap();
+            stream.set_keepalive(None).unwrap();
+
+            (reuse, listener.ttl().unwrap(), stream.nodelay().unwrap(), keepalive,
+             stream.keepalive().unwrap(), stream.linger().unwrap(), stream.ttl().unwrap(),
+             stream.send_buffer_size().unwrap() >= 0x10000,
+             stream.recv_buffer_size().unwrap() >= 0x10000)
+        });
+
+        runtime.run();
+
+        assert_eq!(hdl.join().unwrap(),
+                   ((true, true), 32, true, Some(Duration::from_secs(30)), None,
+                    Some(Duration::from_secs(5)), 64, true, true));
+    }
+
+    #[test]
+    fn test_bind_reuseport() {
+        let runtime = Runtime::new(Config::new().threads(2));
+
+        let listeners = TcpListener::bind_reuseport("127.0.0.1:0", 2).unwrap();
+        let addr = listeners[0].local_addr().unwrap();
+        assert_eq!(listeners[1].local_addr().unwrap(), addr);
+
+        let hdl = runtime.spawn(move|| {
+            // Both processors have to be running, or the pinned coroutines run anywhere
+            while Scheduler::get().stats().processors.len() < 2 {
+                Scheduler::sleep(Duration::from_millis(1));
+            }
+
+            let acceptors: Vec<_> = listeners.into_iter().enumerate().map(|(id, mut listener)| {
+                listener.set_accept_timeout(Some(Duration::from_millis(300))).unwrap();
+                Scheduler::spawn_opts(move|| {
+                    let mut accepted = 0;
+                    while let Ok(_stream) = listener.accept() {
+                        assert_eq!(Processor::current().id(), id);
+                        accepted += 1;
+                    }
+                    accepted
+                }, Options::new().pin_to(id))
+            }).collect();
+
+            let streams: Vec<_> = (0..8).map(|_| TcpStream::connect(&addr).unwrap()).collect();
+            let accepted = acceptors.into_iter().fold(0, |sum, h| sum + h.join().unwrap());
+            drop(streams);
+            accepted
+        });
+
+        runtime.run();
+
+        assert_eq!(hdl.join().unwrap(), 8);
     }
 }
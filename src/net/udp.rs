@@ -23,13 +23,14 @@
 
 use std::ops::{Deref, DerefMut};
 use std::io;
-use std::net::{ToSocketAddrs, SocketAddr};
+use std::net::SocketAddr;
 
 use mio::EventSet;
 
 use bytes::{Buf, MutBuf, SliceBuf, MutSliceBuf};
 
 use processor::Processor;
+use net::ToSocketAddrs;
 
 pub struct UdpSocket(::mio::udp::UdpSocket);
 
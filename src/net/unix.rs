@@ -0,0 +1,758 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Unix domain sockets
+//!
+//! Like the TCP and UDP sockets, every operation looks blocking, but only parks the current
+//! coroutine until the socket is ready. File descriptors can be passed to the peer with
+//! `send_fds` and `recv_fds`, which carry them in a `SCM_RIGHTS` control message.
+
+use std::io;
+use std::mem;
+use std::ptr;
+use std::ops::{Deref, DerefMut};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{RawFd, AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::ffi::OsStr;
+
+use libc::{c_int, c_void, size_t};
+
+use mio::{EventSet, Evented, Io, TryRead, TryWrite};
+
+use processor::Processor;
+use net::sys;
+use net::tcp::Shutdown;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod ffi {
+    use libc::{c_int, c_void, size_t};
+
+    pub type socklen_t = u32;
+    pub type controllen_t = size_t;
+    pub type cmsglen_t = size_t;
+    // Control messages are aligned to `size_t`
+    pub type cmsg_align_t = size_t;
+
+    #[repr(C)]
+    pub struct sockaddr_un {
+        pub sun_family: u16,
+        pub sun_path: [u8; 108],
+    }
+
+    #[repr(C)]
+    pub struct msghdr {
+        pub msg_name: *mut c_void,
+        pub msg_namelen: socklen_t,
+        pub msg_iov: *mut iovec,
+        pub msg_iovlen: size_t,
+        pub msg_control: *mut c_void,
+        pub msg_controllen: controllen_t,
+        pub msg_flags: c_int,
+    }
+
+    #[repr(C)]
+    pub struct iovec {
+        pub iov_base: *mut c_void,
+        pub iov_len: size_t,
+    }
+
+    #[repr(C)]
+    pub struct cmsghdr {
+        pub cmsg_len: cmsglen_t,
+        pub cmsg_level: c_int,
+        pub cmsg_type: c_int,
+    }
+
+    pub fn sockaddr_un_family() -> sockaddr_un {
+        sockaddr_un {
+            sun_family: super::AF_UNIX as u16,
+            sun_path: [0; 108],
+        }
+    }
+}
+
+#[cfg(any(target_os = "macos",
+          target_os = "ios",
+          target_os = "freebsd",
+          target_os = "dragonfly",
+          target_os = "bitrig",
+          target_os = "openbsd"))]
+mod ffi {
+    use libc::{c_int, c_void, size_t};
+
+    pub type socklen_t = u32;
+    pub type controllen_t = socklen_t;
+    pub type cmsglen_t = socklen_t;
+    // Control messages are aligned to `u32` on Darwin, and to `long` on the other BSDs
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    pub type cmsg_align_t = u32;
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    pub type cmsg_align_t = usize;
+
+    #[repr(C)]
+    pub struct sockaddr_un {
+        pub sun_len: u8,
+        pub sun_family: u8,
+        pub sun_path: [u8; 104],
+    }
+
+    #[repr(C)]
+    pub struct msghdr {
+        pub msg_name: *mut c_void,
+        pub msg_namelen: socklen_t,
+        pub msg_iov: *mut iovec,
+        pub msg_iovlen: c_int,
+        pub msg_control: *mut c_void,
+        pub msg_controllen: controllen_t,
+        pub msg_flags: c_int,
+    }
+
+    #[repr(C)]
+    pub struct iovec {
+        pub iov_base: *mut c_void,
+        pub iov_len: size_t,
+    }
+
+    #[repr(C)]
+    pub struct cmsghdr {
+        pub cmsg_len: cmsglen_t,
+        pub cmsg_level: c_int,
+        pub cmsg_type: c_int,
+    }
+
+    pub fn sockaddr_un_family() -> sockaddr_un {
+        sockaddr_un {
+            sun_len: ::std::mem::size_of::<sockaddr_un>() as u8,
+            sun_family: super::AF_UNIX as u8,
+            sun_path: [0; 104],
+        }
+    }
+}
+
+const AF_UNIX: c_int = 1;
+const SOCK_STREAM: c_int = 1;
+const SOCK_DGRAM: c_int = 2;
+const SCM_RIGHTS: c_int = 1;
+const F_SETFD: c_int = 2;
+const F_GETFL: c_int = 3;
+const F_SETFL: c_int = 4;
+const FD_CLOEXEC: c_int = 1;
+const SHUT_RD: c_int = 0;
+const SHUT_WR: c_int = 1;
+const SHUT_RDWR: c_int = 2;
+
+// Offset of `sun_path` in `sockaddr_un`
+const SUN_PATH_OFFSET: usize = 2;
+
+extern {
+    fn socket(domain: c_int, ty: c_int, protocol: c_int) -> c_int;
+    fn socketpair(domain: c_int, ty: c_int, protocol: c_int, sv: *mut c_int) -> c_int;
+    fn bind(fd: c_int, addr: *const ffi::sockaddr_un, len: ffi::socklen_t) -> c_int;
+    fn connect(fd: c_int, addr: *const ffi::sockaddr_un, len: ffi::socklen_t) -> c_int;
+    fn shutdown(fd: c_int, how: c_int) -> c_int;
+    fn sendmsg(fd: c_int, msg: *const ffi::msghdr, flags: c_int) -> isize;
+    fn recvmsg(fd: c_int, msg: *mut ffi::msghdr, flags: c_int) -> isize;
+    fn fcntl(fd: c_int, cmd: c_int, ...) -> c_int;
+    fn dup(fd: c_int) -> c_int;
+    fn close(fd: c_int) -> c_int;
+}
+
+fn cvt(ret: c_int) -> io::Result<c_int> {
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// `Ok(None)` if the operation would block
+fn nonblock<T>(ret: io::Result<T>) -> io::Result<Option<T>> {
+    match ret {
+        Ok(r) => Ok(Some(r)),
+        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Retry `f` until it doesn't block, parking the current coroutine on `interest` in between
+fn wait_io<E, F, T>(io: &E, interest: EventSet, mut f: F) -> io::Result<T>
+    where E: Evented + AsRawFd,
+          F: FnMut() -> io::Result<Option<T>>
+{
+    loop {
+        match try!(f()) {
+            Some(r) => return Ok(r),
+            None => try!(Processor::current().wait_event(io, interest)),
+        }
+    }
+}
+
+fn setup_fd(fd: RawFd) -> io::Result<RawFd> {
+    unsafe {
+        let ret = cvt(fcntl(fd, F_SETFD, FD_CLOEXEC))
+            .and_then(|_| cvt(fcntl(fd, F_GETFL)))
+            .and_then(|flags| cvt(fcntl(fd, F_SETFL, flags | sys::O_NONBLOCK)));
+
+        match ret {
+            Ok(..) => Ok(fd),
+            Err(err) => {
+                close(fd);
+                Err(err)
+            }
+        }
+    }
+}
+
+fn new_socket(ty: c_int) -> io::Result<Io> {
+    let fd = try!(cvt(unsafe { socket(AF_UNIX, ty, 0) }));
+    setup_fd(fd).map(Io::from_raw_fd)
+}
+
+fn new_pair(ty: c_int) -> io::Result<(RawFd, RawFd)> {
+    let mut fds = [0; 2];
+    try!(cvt(unsafe { socketpair(AF_UNIX, ty, 0, fds.as_mut_ptr()) }));
+
+    match setup_fd(fds[0]) {
+        Ok(..) => {},
+        Err(err) => {
+            unsafe { close(fds[1]); }
+            return Err(err);
+        }
+    }
+    match setup_fd(fds[1]) {
+        Ok(..) => Ok((fds[0], fds[1])),
+        Err(err) => {
+            unsafe { close(fds[0]); }
+            Err(err)
+        }
+    }
+}
+
+fn dup_fd(fd: RawFd) -> io::Result<RawFd> {
+    let fd = try!(cvt(unsafe { dup(fd) }));
+    setup_fd(fd)
+}
+
+fn shutdown_fd(fd: RawFd, how: Shutdown) -> io::Result<()> {
+    let how = match how {
+        Shutdown::Read => SHUT_RD,
+        Shutdown::Write => SHUT_WR,
+        Shutdown::Both => SHUT_RDWR,
+    };
+    cvt(unsafe { shutdown(fd, how) }).map(|_| ())
+}
+
+fn sockaddr_un(path: &Path) -> io::Result<(ffi::sockaddr_un, ffi::socklen_t)> {
+    let mut addr = ffi::sockaddr_un_family();
+    let bytes = path.as_os_str().as_bytes();
+
+    // Needs a terminating NUL
+    if bytes.len() >= addr.sun_path.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "path is too long for a Unix socket"));
+    }
+    if bytes.iter().any(|b| *b == 0) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "path contains a NUL byte"));
+    }
+
+    for (dst, src) in addr.sun_path.iter_mut().zip(bytes.iter()) {
+        *dst = *src;
+    }
+    Ok((addr, (SUN_PATH_OFFSET + bytes.len() + 1) as ffi::socklen_t))
+}
+
+/// `None` for unnamed sockets
+fn sockaddr_path(addr: &ffi::sockaddr_un, len: ffi::socklen_t) -> Option<PathBuf> {
+    let len = len as usize;
+    if len <= SUN_PATH_OFFSET {
+        return None;
+    }
+
+    let path = &addr.sun_path[..len - SUN_PATH_OFFSET];
+    let end = path.iter().position(|b| *b == 0).unwrap_or(path.len());
+    if end == 0 {
+        return None;
+    }
+    Some(PathBuf::from(OsStr::from_bytes(&path[..end])))
+}
+
+fn cmsg_align(len: usize) -> usize {
+    let align = mem::size_of::<ffi::cmsg_align_t>();
+    (len + align - 1) & !(align - 1)
+}
+
+fn cmsg_space(len: usize) -> usize {
+    cmsg_align(mem::size_of::<ffi::cmsghdr>()) + cmsg_align(len)
+}
+
+fn cmsg_len(len: usize) -> usize {
+    cmsg_align(mem::size_of::<ffi::cmsghdr>()) + len
+}
+
+/// Buffer for control messages, aligned for `cmsghdr`
+fn cmsg_buffer(fds: usize) -> Vec<usize> {
+    let len = cmsg_space(fds * mem::size_of::<RawFd>());
+    vec![0; (len + mem::size_of::<usize>() - 1) / mem::size_of::<usize>()]
+}
+
+/// Send `buf` with `fds` attached, to `addr` if given
+fn send_msg(fd: RawFd, buf: &[u8], fds: &[RawFd],
+            addr: Option<&(ffi::sockaddr_un, ffi::socklen_t)>) -> io::Result<usize> {
+    let mut iov = ffi::iovec {
+        iov_base: buf.as_ptr() as *mut c_void,
+        iov_len: buf.len() as size_t,
+    };
+
+    let mut control = cmsg_buffer(fds.len());
+    let mut msg: ffi::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+
+    if let Some(&(ref addr, len)) = addr {
+        msg.msg_name = addr as *const ffi::sockaddr_un as *mut c_void;
+        msg.msg_namelen = len;
+    }
+
+    if !fds.is_empty() {
+        let data_len = fds.len() * mem::size_of::<RawFd>();
+        msg.msg_control = control.as_mut_ptr() as *mut c_void;
+        msg.msg_controllen = cmsg_space(data_len) as ffi::controllen_t;
+
+        unsafe {
+            let cmsg = control.as_mut_ptr() as *mut ffi::cmsghdr;
+            (*cmsg).cmsg_len = cmsg_len(data_len) as ffi::cmsglen_t;
+            (*cmsg).cmsg_level = sys::SOL_SOCKET;
+            (*cmsg).cmsg_type = SCM_RIGHTS;
+
+            let data = (cmsg as *mut u8).offset(cmsg_len(0) as isize) as *mut RawFd;
+            ptr::copy_nonoverlapping(fds.as_ptr(), data, fds.len());
+        }
+    }
+
+    let ret = unsafe { sendmsg(fd, &msg, 0) };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+/// Receive into `buf`, and the passed file descriptors into `fds`
+///
+/// Returns the number of bytes, the number of file descriptors, and the address of the
+/// sender. File descriptors which don't fit into `fds` are closed.
+fn recv_msg(fd: RawFd, buf: &mut [u8], fds: &mut [RawFd])
+        -> io::Result<(usize, usize, Option<PathBuf>)> {
+    let mut iov = ffi::iovec {
+        iov_base: buf.as_mut_ptr() as *mut c_void,
+        iov_len: buf.len() as size_t,
+    };
+
+    let mut addr = ffi::sockaddr_un_family();
+    let mut control = cmsg_buffer(fds.len());
+    let mut msg: ffi::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &mut addr as *mut ffi::sockaddr_un as *mut c_void;
+    msg.msg_namelen = mem::size_of::<ffi::sockaddr_un>() as ffi::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if !fds.is_empty() {
+        msg.msg_control = control.as_mut_ptr() as *mut c_void;
+        msg.msg_controllen = (control.len() * mem::size_of::<usize>()) as ffi::controllen_t;
+    }
+
+    let ret = unsafe { recvmsg(fd, &mut msg, sys::MSG_CMSG_CLOEXEC) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    if msg.msg_flags & sys::MSG_CTRUNC != 0 {
+        warn!("Some file descriptors passed through a Unix socket were discarded");
+    }
+
+    let mut received = 0;
+    let control_start = control.as_ptr() as usize;
+    let control_end = control_start + msg.msg_controllen as usize;
+    let mut pos = if msg.msg_control.is_null() { control_end } else { control_start };
+
+    while pos + cmsg_len(0) <= control_end {
+        let cmsg = unsafe { &*(pos as *const ffi::cmsghdr) };
+        let len = cmsg.cmsg_len as usize;
+        if len < cmsg_len(0) || pos + len > control_end {
+            break;
+        }
+
+        if cmsg.cmsg_level == sys::SOL_SOCKET && cmsg.cmsg_type == SCM_RIGHTS {
+            let data = (pos + cmsg_len(0)) as *const RawFd;
+            let count = (len - cmsg_len(0)) / mem::size_of::<RawFd>();
+
+            for i in 0..count {
+                let passed = unsafe { *data.offset(i as isize) };
+                if received < fds.len() {
+                    // `MSG_CMSG_CLOEXEC` is not available everywhere
+                    unsafe { fcntl(passed, F_SETFD, FD_CLOEXEC); }
+                    fds[received] = passed;
+                    received += 1;
+                } else {
+                    unsafe { close(passed); }
+                }
+            }
+        }
+
+        pos += cmsg_align(len);
+    }
+
+    Ok((ret as usize, received, sockaddr_path(&addr, msg.msg_namelen)))
+}
+
+/// A Unix stream socket server
+#[derive(Debug)]
+pub struct UnixListener(::mio::unix::UnixListener);
+
+impl UnixListener {
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<UnixListener> {
+        ::mio::unix::UnixListener::bind(path.as_ref()).map(UnixListener)
+    }
+
+    pub fn accept(&self) -> io::Result<UnixStream> {
+        wait_io(&self.0, EventSet::readable(), || self.0.accept()).map(UnixStream)
+    }
+
+    pub fn try_clone(&self) -> io::Result<UnixListener> {
+        Ok(UnixListener(try!(self.0.try_clone())))
+    }
+
+    pub fn incoming<'a>(&'a self) -> Incoming<'a> {
+        Incoming(self)
+    }
+}
+
+impl Deref for UnixListener {
+    type Target = ::mio::unix::UnixListener;
+
+    fn deref(&self) -> &::mio::unix::UnixListener {
+        &self.0
+    }
+}
+
+impl DerefMut for UnixListener {
+    fn deref_mut(&mut self) -> &mut ::mio::unix::UnixListener {
+        &mut self.0
+    }
+}
+
+impl AsRawFd for UnixListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+pub struct Incoming<'a>(&'a UnixListener);
+
+impl<'a> Iterator for Incoming<'a> {
+    type Item = io::Result<UnixStream>;
+
+    fn next(&mut self) -> Option<io::Result<UnixStream>> {
+        Some(self.0.accept())
+    }
+}
+
+/// A Unix stream socket
+#[derive(Debug)]
+pub struct UnixStream(::mio::unix::UnixStream);
+
+impl UnixStream {
+    pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<UnixStream> {
+        let sock = try!(::mio::unix::UnixSocket::stream());
+        let (stream, completed) = try!(sock.connect(path.as_ref()));
+
+        if !completed {
+            try!(Processor::current().wait_event(&stream, EventSet::writable()));
+        }
+        Ok(UnixStream(stream))
+    }
+
+    /// Create a pair of connected sockets
+    pub fn pair() -> io::Result<(UnixStream, UnixStream)> {
+        let (a, b) = try!(new_pair(SOCK_STREAM));
+        unsafe {
+            Ok((UnixStream(FromRawFd::from_raw_fd(a)), UnixStream(FromRawFd::from_raw_fd(b))))
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<UnixStream> {
+        Ok(UnixStream(try!(self.0.try_clone())))
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        shutdown_fd(self.0.as_raw_fd(), how)
+    }
+
+    /// Write `buf` with `fds` attached, the file descriptors are duplicated into the peer
+    pub fn send_fds(&self, buf: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+        let fd = self.0.as_raw_fd();
+        wait_io(&self.0, EventSet::writable(), || nonblock(send_msg(fd, buf, fds, None)))
+    }
+
+    /// Read into `buf` and receive the attached file descriptors into `fds`, returns the
+    /// number of bytes and the number of file descriptors
+    ///
+    /// The received file descriptors are owned by the caller.
+    pub fn recv_fds(&self, buf: &mut [u8], fds: &mut [RawFd]) -> io::Result<(usize, usize)> {
+        let fd = self.0.as_raw_fd();
+        wait_io(&self.0, EventSet::readable(), || nonblock(recv_msg(fd, buf, fds)))
+            .map(|(len, nfds, _)| (len, nfds))
+    }
+}
+
+impl io::Read for UnixStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let inner = &mut self.0;
+        match try!(inner.try_read(buf)) {
+            Some(len) => return Ok(len),
+            None => debug!("UnixStream read WouldBlock"),
+        }
+
+        loop {
+            try!(Processor::current().wait_event(inner, EventSet::readable()));
+
+            match try!(inner.try_read(buf)) {
+                Some(len) => return Ok(len),
+                None => warn!("UnixStream read WouldBlock; Coroutine was awaked by readable event"),
+            }
+        }
+    }
+}
+
+impl io::Write for UnixStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let inner = &mut self.0;
+        match try!(inner.try_write(buf)) {
+            Some(len) => return Ok(len),
+            None => debug!("UnixStream write WouldBlock"),
+        }
+
+        loop {
+            try!(Processor::current().wait_event(inner, EventSet::writable()));
+
+            match try!(inner.try_write(buf)) {
+                Some(len) => return Ok(len),
+                None => warn!("UnixStream write WouldBlock; Coroutine was awaked by writable event"),
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Deref for UnixStream {
+    type Target = ::mio::unix::UnixStream;
+
+    fn deref(&self) -> &::mio::unix::UnixStream {
+        &self.0
+    }
+}
+
+impl DerefMut for UnixStream {
+    fn deref_mut(&mut self) -> &mut ::mio::unix::UnixStream {
+        &mut self.0
+    }
+}
+
+impl AsRawFd for UnixStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl FromRawFd for UnixStream {
+    /// The file descriptor must be a non-blocking Unix stream socket
+    unsafe fn from_raw_fd(fd: RawFd) -> UnixStream {
+        UnixStream(FromRawFd::from_raw_fd(fd))
+    }
+}
+
+/// A Unix datagram socket
+#[derive(Debug)]
+pub struct UnixDatagram(Io);
+
+impl UnixDatagram {
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<UnixDatagram> {
+        let io = try!(new_socket(SOCK_DGRAM));
+        let (addr, len) = try!(sockaddr_un(path.as_ref()));
+        try!(cvt(unsafe { bind(io.as_raw_fd(), &addr, len) }));
+        Ok(UnixDatagram(io))
+    }
+
+    /// Create a socket which is not bound to any path
+    pub fn unbound() -> io::Result<UnixDatagram> {
+        new_socket(SOCK_DGRAM).map(UnixDatagram)
+    }
+
+    /// Create a pair of connected sockets
+    pub fn pair() -> io::Result<(UnixDatagram, UnixDatagram)> {
+        let (a, b) = try!(new_pair(SOCK_DGRAM));
+        Ok((UnixDatagram(Io::from_raw_fd(a)), UnixDatagram(Io::from_raw_fd(b))))
+    }
+
+    /// Set the default destination of `send`, and only receive from it
+    pub fn connect<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let (addr, len) = try!(sockaddr_un(path.as_ref()));
+        cvt(unsafe { connect(self.0.as_raw_fd(), &addr, len) }).map(|_| ())
+    }
+
+    pub fn try_clone(&self) -> io::Result<UnixDatagram> {
+        dup_fd(self.0.as_raw_fd()).map(|fd| UnixDatagram(Io::from_raw_fd(fd)))
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        shutdown_fd(self.0.as_raw_fd(), how)
+    }
+
+    pub fn send_to<P: AsRef<Path>>(&self, buf: &[u8], path: P) -> io::Result<usize> {
+        let addr = try!(sockaddr_un(path.as_ref()));
+        let fd = self.0.as_raw_fd();
+        wait_io(&self.0, EventSet::writable(), || nonblock(send_msg(fd, buf, &[], Some(&addr))))
+    }
+
+    /// Receive a datagram, the address is `None` if the sender is not bound
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, Option<PathBuf>)> {
+        let fd = self.0.as_raw_fd();
+        wait_io(&self.0, EventSet::readable(), || nonblock(recv_msg(fd, buf, &mut [])))
+            .map(|(len, _, addr)| (len, addr))
+    }
+
+    /// Send to the connected peer
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.send_fds(buf, &[])
+    }
+
+    /// Receive from the connected peer
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.recv_fds(buf, &mut []).map(|(len, _)| len)
+    }
+
+    /// Send to the connected peer with `fds` attached
+    pub fn send_fds(&self, buf: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+        let fd = self.0.as_raw_fd();
+        wait_io(&self.0, EventSet::writable(), || nonblock(send_msg(fd, buf, fds, None)))
+    }
+
+    /// Receive a datagram and the attached file descriptors, returns the number of bytes
+    /// and the number of file descriptors
+    pub fn recv_fds(&self, buf: &mut [u8], fds: &mut [RawFd]) -> io::Result<(usize, usize)> {
+        let fd = self.0.as_raw_fd();
+        wait_io(&self.0, EventSet::readable(), || nonblock(recv_msg(fd, buf, fds)))
+            .map(|(len, nfds, _)| (len, nfds))
+    }
+}
+
+impl AsRawFd for UnixDatagram {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl FromRawFd for UnixDatagram {
+    /// The file descriptor must be a non-blocking Unix datagram socket
+    unsafe fn from_raw_fd(fd: RawFd) -> UnixDatagram {
+        UnixDatagram(Io::from_raw_fd(fd))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use runtime::{Runtime, Config};
+    use scheduler::Scheduler;
+    use net::tcp::Shutdown;
+
+    use super::{UnixListener, UnixStream, UnixDatagram};
+
+    #[test]
+    fn test_unix_stream() {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+        let path = env::temp_dir().join(format!("simplesched-{}.sock", nanos));
+        let runtime = Runtime::new(Config::new());
+
+        let their_path = path.clone();
+        let hdl = runtime.spawn(move|| {
+            let listener = UnixListener::bind(&their_path).unwrap();
+
+            let server = Scheduler::spawn(move|| {
+                let mut stream = listener.incoming().next().unwrap().unwrap();
+                let mut received = String::new();
+                stream.read_to_string(&mut received).unwrap();
+                stream.write_all(received.as_bytes()).unwrap();
+            });
+
+            let mut stream = UnixStream::connect(&their_path).unwrap();
+            stream.write_all(b"hello").unwrap();
+            stream.shutdown(Shutdown::Write).unwrap();
+
+            let mut echoed = String::new();
+            stream.read_to_string(&mut echoed).unwrap();
+            server.join().unwrap();
+            echoed
+        });
+
+        runtime.run();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(hdl.join().unwrap(), "hello");
+    }
+
+    #[test]
+    fn test_pass_fds() {
+        let runtime = Runtime::new(Config::new());
+
+        let hdl = runtime.spawn(|| {
+            let (sender, receiver) = UnixDatagram::pair().unwrap();
+            let (mut local, remote) = UnixStream::pair().unwrap();
+
+            sender.send_fds(b"fd", &[remote.as_raw_fd()]).unwrap();
+            drop(remote);
+
+            let mut buf = [0u8; 16];
+            let mut fds = [-1; 2];
+            let (len, nfds) = receiver.recv_fds(&mut buf, &mut fds).unwrap();
+
+            // The duplicated end is still connected to `local`
+            let mut passed = unsafe { UnixStream::from_raw_fd(fds[0]) };
+            passed.write_all(b"through").unwrap();
+            drop(passed);
+
+            let mut received = String::new();
+            local.read_to_string(&mut received).unwrap();
+            (buf[..len].to_vec(), nfds, received)
+        });
+
+        runtime.run();
+
+        assert_eq!(hdl.join().unwrap(), (b"fd".to_vec(), 1, "through".to_owned()));
+    }
+}
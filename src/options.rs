@@ -24,10 +24,25 @@
 use std::rt;
 use std::default::Default;
 
+/// Scheduling priority of a coroutine
+///
+/// A processor runs the coroutines in its local queues by priority, but the lower priorities
+/// still get their turns periodically. The global queue and the pinned coroutines are FIFO.
+/// A new coroutine runs immediately only if its priority is not lower than its parent's.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    High,
+    Normal,
+    Low,
+}
+
 /// Coroutine options
 pub struct Options {
     pub stack_size: usize,
     pub name: Option<String>,
+    pub pinned: bool,
+    pub processor: Option<usize>,
+    pub priority: Priority,
 }
 
 impl Options {
@@ -35,9 +50,15 @@ impl Options {
         Options {
             stack_size: rt::min_stack(),
             name: None,
+            pinned: false,
+            processor: None,
+            priority: Priority::Normal,
         }
     }
 
+    /// Size of the stack, including the guard page at its lowest end
+    ///
+    /// Overflowing the stack aborts the process with the ID and name of the coroutine.
     pub fn stack_size(mut self, size: usize) -> Options {
         self.stack_size = size;
         self
@@ -47,6 +68,27 @@ impl Options {
         self.name = name;
         self
     }
+
+    /// Always resume the coroutine on the processor which runs it first
+    ///
+    /// For code which keeps thread local state between the suspensions, like FFI libraries.
+    pub fn pinned(mut self, pinned: bool) -> Options {
+        self.pinned = pinned;
+        self
+    }
+
+    /// Always resume the coroutine on the processor `processor_id`
+    ///
+    /// It runs on any processor until the processor `processor_id` starts scheduling.
+    pub fn pin_to(mut self, processor_id: usize) -> Options {
+        self.processor = Some(processor_id);
+        self
+    }
+
+    pub fn priority(mut self, priority: Priority) -> Options {
+        self.priority = priority;
+        self
+    }
 }
 
 impl Default for Options {
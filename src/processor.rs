@@ -23,74 +23,298 @@
 
 use std::cell::UnsafeCell;
 use std::io;
-#[cfg(target_os = "linux")]
 use std::os::unix::io::AsRawFd;
-#[cfg(target_os = "linux")]
 use std::convert::From;
-use std::sync::Arc;
-use std::thread;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::mem;
+use std::ptr;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use mio::{EventLoop, Evented, Handler, Token, EventSet, PollOpt};
+use mio::{EventLoop, EventLoopConfig, Evented, Handler, Token, EventSet, PollOpt, Timeout};
 use mio::util::Slab;
-#[cfg(target_os = "linux")]
 use mio::Io;
 
 use mio::util::BoundedQueue;
 
-use scheduler::{Scheduler, CoroutineRefMut};
+use scheduler::{Scheduler, CoroutineRefMut, ProcessorHandle};
 use coroutine::{self, Coroutine, State, Handle};
-use options::Options;
+use options::{Options, Priority};
+use join_handle::{JoinHandle, Aborted};
+use stats::Counters;
+use stack_guard;
+use affinity;
+
+// Boxed, so the processor won't move when it is replaced by `Processor::run`
+thread_local!(static PROCESSOR: UnsafeCell<Option<Box<Processor>>> = UnsafeCell::new(None));
+
+// Take one coroutine from the global queue every these ticks, even though the local
+// queue is not empty, so the global queue won't be starved.
+const GLOBAL_QUEUE_CHECK_INTERVAL: usize = 61;
+
+// Try the normal and the low priority local queues before the higher ones every these
+// ticks, so they won't be starved by a steady stream of higher priority coroutines.
+const NORMAL_PRIORITY_INTERVAL: usize = 7;
+const LOW_PRIORITY_INTERVAL: usize = 31;
+
+// Poll the event loop without blocking every these ticks, so I/O events and timers
+// won't be starved by a busy processor.
+const EVENT_LOOP_POLL_INTERVAL: usize = 61;
+
+// Resolution of the timers in the event loop
+const TIMER_TICK_MS: u64 = 10;
+
+#[doc(hidden)]
+/// Messages sent to the event loop of a processor
+#[derive(Debug)]
+pub enum Message {
+    /// New works are available, stop waiting for I/O events
+    Wakeup,
+    /// The coroutine waiting for I/O with the token is aborted
+    CancelIo(Token, CoroutineRefMut),
+    /// The coroutine sleeping with the token is aborted
+    CancelSleep(Token, CoroutineRefMut),
+}
+
+// Round up to the resolution of the event loop
+fn duration_to_ms(dur: &Duration) -> u64 {
+    dur.as_secs() * 1_000 + (dur.subsec_nanos() as u64 + 999_999) / 1_000_000
+}
 
-thread_local!(static PROCESSOR: UnsafeCell<Processor> = UnsafeCell::new(Processor::new()));
+/// Timers registered in the event loop of a processor
+#[derive(Debug)]
+enum Timer {
+    /// Wake up the sleeping coroutine registered with the token
+    Sleep(Token),
+    /// Give up waiting for the I/O event registered with the token
+    Io(Token),
+    /// Wake up the event loop when the drain period of shutdown is over
+    Drain,
+}
 
 /// Processing unit of a thread
 pub struct Processor {
+    id: usize,
+    scheduler: Arc<Scheduler>,
     event_loop: EventLoop<IoHandler>,
-    work_queue: Arc<BoundedQueue<CoroutineRefMut>>,
+    local_queues: Vec<Arc<BoundedQueue<CoroutineRefMut>>>,
+    handle: Arc<ProcessorHandle>,
+    is_scheduling: bool,
+    draining: bool,
+    tick: usize,
     handler: IoHandler,
     main_coro: Handle,
     cur_running: Option<CoroutineRefMut>,
     last_result: Option<coroutine::Result<State>>,
     new_spawned: Option<CoroutineRefMut>,
+    block_callback: Option<*mut FnMut(CoroutineRefMut)>,
 }
 
 impl Processor {
     #[doc(hidden)]
-    pub fn new() -> Processor {
+    pub fn new(scheduler: Arc<Scheduler>, id: usize) -> Processor {
         let main_coro = unsafe {
-            Coroutine::empty()
+            Coroutine::empty(scheduler.clone())
         };
 
+        let config = EventLoopConfig {
+            timer_tick_ms: TIMER_TICK_MS,
+            .. Default::default()
+        };
+        let event_loop = EventLoop::configured(config).unwrap();
+        // One for each `Priority`
+        let local_queues: Vec<_> = (0..3).map(|_| {
+            Arc::new(BoundedQueue::with_capacity(scheduler.config().local_queue_size))
+        }).collect();
+        let handle = Arc::new(ProcessorHandle {
+            id: id,
+            queues: local_queues.clone(),
+            sender: event_loop.channel(),
+            idle: AtomicBool::new(false),
+            counters: Counters::new(),
+            inbox: Mutex::new(VecDeque::new()),
+            inbox_len: AtomicUsize::new(0),
+        });
+
+        let handler = IoHandler::new(handle.clone());
+
         Processor {
-            event_loop: EventLoop::new().unwrap(),
-            work_queue: Scheduler::get().get_queue(),
-            handler: IoHandler::new(),
+            id: id,
+            scheduler: scheduler,
+            event_loop: event_loop,
+            local_queues: local_queues,
+            handle: handle,
+            is_scheduling: false,
+            draining: false,
+            tick: 0,
+            handler: handler,
             main_coro: main_coro,
             cur_running: None,
             last_result: None,
             new_spawned: None,
+            block_callback: None,
         }
     }
 
     #[doc(hidden)]
-    pub fn running(&mut self) -> Option<CoroutineRefMut> {
+    pub fn running(&self) -> Option<CoroutineRefMut> {
         self.cur_running
     }
 
+    #[doc(hidden)]
+    /// The running coroutine, or the main coroutine of this processor if nothing is running
+    pub fn current_coroutine(&mut self) -> *mut Coroutine {
+        match self.cur_running {
+            Some(coro) => coro.coro_ptr,
+            None => &mut *self.main_coro,
+        }
+    }
+
+    /// ID of this processor
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// The scheduler which this processor belongs to
+    pub fn scheduler(&self) -> &Arc<Scheduler> {
+        &self.scheduler
+    }
+
+    #[doc(hidden)]
+    pub fn belongs_to(&self, scheduler: &Scheduler) -> bool {
+        &*self.scheduler as *const Scheduler == scheduler as *const Scheduler
+    }
+
     /// Get the thread local processor
+    ///
+    /// A processor of the global scheduler is created if this thread doesn't have one. The
+    /// paths which may run on any thread use `try_current` instead, so they won't create it.
     pub fn current() -> &'static mut Processor {
-        PROCESSOR.with(|p| unsafe { &mut *p.get() })
+        PROCESSOR.with(|p| unsafe {
+            let p = &mut *p.get();
+            if p.is_none() {
+                let scheduler = Scheduler::global().clone();
+                let id = scheduler.next_processor_id();
+                *p = Some(Box::new(Processor::new(scheduler, id)));
+            }
+
+            &mut **p.as_mut().unwrap()
+        })
+    }
+
+    #[doc(hidden)]
+    /// Get the thread local processor, if this thread has one
+    pub fn try_current() -> Option<&'static mut Processor> {
+        PROCESSOR.with(|p| unsafe {
+            (&mut *p.get()).as_mut().map(|p| &mut **p)
+        })
+    }
+
+    #[doc(hidden)]
+    /// Run a new processor of `scheduler` with `id` in this thread until all the works
+    /// are done
+    ///
+    /// The new processor replaces the thread local processor during the run. Must not be
+    /// called inside a coroutine.
+    pub fn run(scheduler: Arc<Scheduler>, id: usize) {
+        stack_guard::init();
+
+        // The thread may be the one calling `Scheduler::run`, which keeps running after it
+        let saved_mask = if scheduler.cpu_affinity() {
+            let cpu = id % affinity::cpus();
+            match affinity::mask().and_then(|mask| affinity::set_cpu(cpu).map(|_| mask)) {
+                Ok(mask) => Some(mask),
+                Err(err) => {
+                    warn!("Processor {} cannot be pinned to CPU {}: {}", id, cpu, err);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let processor = Some(Box::new(Processor::new(scheduler, id)));
+        let prev = PROCESSOR.with(|p| unsafe { mem::replace(&mut *p.get(), processor) });
+
+        if let Err(err) = Processor::current().schedule() {
+            error!("Processor schedule error: {:?}", err);
+        }
+
+        PROCESSOR.with(|p| unsafe { *p.get() = prev; });
+
+        if let Some(mask) = saved_mask {
+            if let Err(err) = affinity::set_mask(&mask) {
+                warn!("Processor {} cannot restore the CPU affinity: {}", id, err);
+            }
+        }
     }
 
     /// Spawn a new coroutine and run it in this processor immediately
-    pub fn spawn_opts<F>(&mut self, f: F, opts: Options)
-        where F: FnOnce() + Send + 'static
+    ///
+    /// A coroutine with a lower priority than the running one is queued instead, so it
+    /// won't run ahead of its parent.
+    pub fn spawn_opts<F, T>(&mut self, f: F, opts: Options) -> JoinHandle<T>
+        where F: FnOnce() -> T + Send + 'static,
+              T: Send + 'static
     {
-        let coro = Coroutine::spawn_opts(f, opts);
-        let coro = CoroutineRefMut::new(unsafe { mem::transmute(coro) });
-        self.new_spawned = Some(coro);
-        self.sched();
+        let (coro, handle) = match Scheduler::new_coroutine(&self.scheduler, f, opts) {
+            (Some(coro), handle) => (coro, handle),
+            (None, handle) => return handle,
+        };
+
+        let immediately = match self.cur_running {
+            // `High` is the smallest one
+            Some(parent) => unsafe {
+                (&*coro.coro_ptr).priority() as usize <= (&*parent.coro_ptr).priority() as usize
+            },
+            None => false,
+        };
+
+        if immediately {
+            self.new_spawned = Some(coro);
+            self.sched();
+        } else {
+            self.ready(coro);
+        }
+
+        handle
+    }
+
+    #[doc(hidden)]
+    /// Push a ready coroutine into the local queue, the global queue will be used
+    /// if the local queue is full or this processor is not scheduling
+    ///
+    /// Pinned coroutines go to the inbox of their processor instead, which is never stolen.
+    pub fn ready(&mut self, coro: CoroutineRefMut) {
+        if let Some(id) = unsafe { (&*coro.coro_ptr).pinned() } {
+            if self.is_scheduling && id == self.id {
+                return self.handle.push_inbox(coro);
+            }
+
+            if self.scheduler.push_pinned(coro, id).is_ok() {
+                return;
+            }
+        }
+
+        if !self.is_scheduling {
+            return self.scheduler.push_global(coro);
+        }
+
+        // Counted before being pushed, so a concurrent steal won't make it negative
+        let depth = &self.handle.counters.queue_depth;
+        depth.fetch_add(1, Ordering::Relaxed);
+
+        let priority = unsafe { (&*coro.coro_ptr).priority() };
+        match self.local_queues[priority as usize].push(coro) {
+            // Let the idle processors steal it
+            Ok(..) => self.scheduler.unpark_one(),
+            Err(coro) => {
+                depth.fetch_sub(1, Ordering::Relaxed);
+                self.scheduler.push_global(coro);
+            }
+        }
     }
 
     #[doc(hidden)]
@@ -99,36 +323,157 @@ impl Processor {
     }
 
     fn run_task(&mut self, hdl: CoroutineRefMut) {
+        if unsafe { (&*hdl.coro_ptr).is_abandoned() } {
+            // Waked up from a wait list after its scheduler had shut down
+            return Scheduler::free_abandoned(hdl);
+        }
+
+        let coro = unsafe { &mut *hdl.coro_ptr };
+        match coro.pinned() {
+            None if coro.pin_on_resume() => coro.set_pinned(Some(self.id)),
+            // Pinned coroutines taken from the global queue may belong to another processor
+            Some(id) if id != self.id => {
+                if self.scheduler.push_pinned(hdl, id).is_ok() {
+                    return;
+                }
+            },
+            _ => {},
+        }
+
+        Counters::incr(&self.handle.counters.context_switches);
+
         match self.resume(hdl) {
             Ok(State::Suspended) => {
-                Scheduler::ready(hdl);
+                unsafe { (&*hdl.coro_ptr).status().set_suspended(); }
+                self.ready(hdl);
             },
             Ok(State::Finished) | Ok(State::Panicked) => {
+                Counters::incr(&self.handle.counters.coroutines_finished);
+                unsafe { (&mut *hdl.coro_ptr).finish(None); }
                 Scheduler::finished(hdl);
             },
-            Ok(State::Blocked) => (),
-            Err(err) => {
-                error!("Coroutine resume failed, {:?}", err);
+            Ok(State::Blocked) => {
+                // It may be resumed by another processor once the callback is called
+                unsafe { (&*hdl.coro_ptr).status().set_blocked(); }
+                if let Some(callback) = self.block_callback.take() {
+                    unsafe { (&mut *callback)(hdl); }
+                }
+            },
+            Err(coroutine::Error::Panicking(err)) => {
+                let info = unsafe { (&*hdl.coro_ptr).info() };
+                if err.is::<Aborted>() {
+                    debug!("{} aborted", info);
+                } else {
+                    error!("{} panicked", info);
+                    Counters::incr(&self.handle.counters.panics);
+                }
+                Counters::incr(&self.handle.counters.coroutines_finished);
+                unsafe { (&mut *hdl.coro_ptr).finish(Some(err)); }
                 Scheduler::finished(hdl);
             }
         }
     }
 
+    fn next_task(&mut self) -> Option<CoroutineRefMut> {
+        self.tick = self.tick.wrapping_add(1);
+
+        let scheduler = &self.scheduler;
+
+        if self.tick % GLOBAL_QUEUE_CHECK_INTERVAL == 0 {
+            if let Some(hdl) = scheduler.pop_global() {
+                return Some(hdl);
+            }
+        }
+
+        // Take turns with the local queue, so neither of them is starved
+        if self.tick % 2 == 0 {
+            if let Some(hdl) = self.handle.pop_inbox() {
+                return Some(hdl);
+            }
+        }
+
+        let handle = &self.handle;
+        self.pop_local()
+            .or_else(|| handle.pop_inbox())
+            .or_else(|| scheduler.pop_global())
+            .or_else(|| scheduler.steal(self.id))
+    }
+
+    /// Pop from the local queues by priority, the lower priorities go first on some ticks
+    fn pop_local(&mut self) -> Option<CoroutineRefMut> {
+        let order = if self.tick % LOW_PRIORITY_INTERVAL == 0 {
+            [Priority::Low, Priority::Normal, Priority::High]
+        } else if self.tick % NORMAL_PRIORITY_INTERVAL == 0 {
+            [Priority::Normal, Priority::High, Priority::Low]
+        } else {
+            [Priority::High, Priority::Normal, Priority::Low]
+        };
+
+        let queues = &self.local_queues;
+        let hdl = order.iter()
+            .filter_map(|&priority| queues[priority as usize].pop())
+            .next();
+
+        if hdl.is_some() {
+            self.handle.counters.queue_depth.fetch_sub(1, Ordering::Relaxed);
+        }
+        hdl
+    }
+
     #[doc(hidden)]
     pub fn schedule(&mut self) -> io::Result<()> {
+        self.scheduler.register_processor(self.handle.clone());
+        self.is_scheduling = true;
+
+        let result = self.schedule_loop();
+
+        self.is_scheduling = false;
+        self.scheduler.unregister_processor(self.id);
+
+        // Nobody would wake them up once this processor is gone
+        for hdl in self.handler.take_waiters(&mut self.event_loop) {
+            Scheduler::abandon(hdl);
+        }
+
+        // Hand the remaining coroutines over to the other processors
+        while let Some(hdl) = self.pop_local() {
+            self.scheduler.push_global(hdl);
+        }
+        while let Some(hdl) = self.handle.pop_inbox() {
+            self.scheduler.push_global(hdl);
+        }
+
+        result
+    }
+
+    fn schedule_loop(&mut self) -> io::Result<()> {
         loop {
-            match self.work_queue.pop() {
+            self.handler.publish();
+
+            if self.tick % EVENT_LOOP_POLL_INTERVAL == 0 {
+                if self.handler.has_waiters() {
+                    try!(self.poll_events());
+                }
+
+                if self.drained() {
+                    break;
+                }
+            }
+
+            match self.next_task() {
                 Some(hdl) => {
                     self.run_task(hdl)
                 },
                 None => {
-                    if self.handler.slabs.count() != 0 {
-                        try!(self.event_loop.run_once(&mut self.handler));
-                    } else if Scheduler::get().work_count() == 0 {
+                    if !self.handler.has_waiters() && self.scheduler.work_count() == 0 {
                         break;
-                    } else {
-                        thread::sleep_ms(100);
                     }
+
+                    if self.drained() {
+                        break;
+                    }
+
+                    try!(self.park());
                 }
             }
 
@@ -140,12 +485,79 @@ impl Processor {
         Ok(())
     }
 
+    /// Whether the scheduler is shutting down and the drain period is over
+    ///
+    /// The coroutines waiting for I/O are interrupted when the shutdown is seen first time.
+    fn drained(&mut self) -> bool {
+        if !self.scheduler.is_shutdown() {
+            return false;
+        }
+
+        let deadline = match self.scheduler.drain_deadline() {
+            Some(deadline) => deadline,
+            None => return false,
+        };
+        let now = Instant::now();
+
+        if !self.draining {
+            self.draining = true;
+            debug!("Processor {} is draining", self.id);
+            self.handler.interrupt(&mut self.event_loop);
+
+            // Make sure the event loop won't block after the deadline
+            if deadline > now {
+                let delay = duration_to_ms(&(deadline - now));
+                if let Err(err) = self.event_loop.timeout_ms(Timer::Drain, delay) {
+                    error!("Failed to register timer: {:?}", err);
+                }
+            }
+        }
+
+        now >= deadline
+    }
+
+    /// Wait for I/O events or new works, until being waked up by `Scheduler::unpark_one`
+    fn park(&mut self) -> io::Result<()> {
+        let scheduler = self.scheduler.clone();
+        scheduler.park(&self.handle);
+
+        // Check again after being marked as idle, works pushed before that would not wake us up
+        let hdl = self.handle.pop_inbox()
+            .or_else(|| scheduler.pop_global())
+            .or_else(|| scheduler.steal(self.id));
+        if hdl.is_none() && (self.handler.has_waiters() || scheduler.work_count() != 0) {
+            let start = Instant::now();
+            try!(self.event_loop.run_once(&mut self.handler));
+            self.handle.counters.add_idle(start.elapsed());
+        }
+
+        scheduler.unpark(&self.handle);
+
+        if let Some(hdl) = hdl {
+            self.run_task(hdl);
+        }
+
+        Ok(())
+    }
+
+    /// Process the ready I/O events and timers without blocking
+    fn poll_events(&mut self) -> io::Result<()> {
+        // The event loop won't block if there are pending messages
+        let _ = self.handle.sender.send(Message::Wakeup);
+        self.event_loop.run_once(&mut self.handler)
+    }
+
     #[doc(hidden)]
     pub fn resume(&mut self, coro_ref: CoroutineRefMut) -> coroutine::Result<State> {
         self.cur_running = Some(coro_ref);
         unsafe {
+            let coro = &*coro_ref.coro_ptr;
+            coro.status().set_running();
+            stack_guard::set_running(coro.running_guard(), coro.info());
+
             self.main_coro.yield_to(&mut *coro_ref.coro_ptr);
         }
+        stack_guard::set_running(None, ptr::null());
 
         match self.last_result.take() {
             None => Ok(State::Suspended),
@@ -153,8 +565,22 @@ impl Processor {
         }
     }
 
+    #[doc(hidden)]
+    /// Unwind the current running coroutine if it has been aborted
+    pub fn check_aborted(&self) {
+        if let Some(coro) = self.cur_running {
+            if unsafe { (&*coro.coro_ptr).is_aborted() } && !thread::panicking() {
+                panic!(Aborted);
+            }
+        }
+    }
+
     /// Suspended the current running coroutine, equivalent to `Scheduler::sched`
+    ///
+    /// The coroutine is unwound if it has been aborted.
     pub fn sched(&mut self) {
+        self.check_aborted();
+
         match self.cur_running.take() {
             None => {},
             Some(coro_ref) => unsafe {
@@ -175,6 +601,95 @@ impl Processor {
         }
     }
 
+    /// Block the current running coroutine and hand it to `f` after it has been switched out
+    ///
+    /// `f` runs on the processor's stack, so it is safe for `f` to put the coroutine into a
+    /// wait list which may be woken by another thread. Must be called inside a coroutine.
+    pub fn block_with<F>(&mut self, f: F)
+        where F: FnOnce(CoroutineRefMut)
+    {
+        let mut f = Some(f);
+        let mut callback = |coro: CoroutineRefMut| {
+            if let Some(f) = f.take() {
+                f(coro);
+            }
+        };
+
+        {
+            let callback: &mut FnMut(CoroutineRefMut) = &mut callback;
+            // The callback lives on the coroutine's stack, which is kept alive until
+            // the callback has been called in `run_task`
+            self.block_callback = Some(unsafe { mem::transmute(callback) });
+        }
+        // The callback is taken by `run_task`, and the coroutine may be resumed by another
+        // processor, so `self` must not be touched after this
+        self.block();
+    }
+
+    /// Block the current running coroutine for `dur`, equivalent to `Scheduler::sleep`
+    ///
+    /// The current thread will be blocked if it is not called inside a coroutine. The
+    /// coroutine is unwound if it has been aborted.
+    pub fn sleep(&mut self, dur: Duration) {
+        let coro = match self.cur_running {
+            None => return thread::sleep(dur),
+            Some(coro) => coro,
+        };
+
+        let delay = duration_to_ms(&dur);
+        if delay == 0 {
+            return self.sched();
+        }
+
+        self.check_aborted();
+
+        // If there are too many timers, the only option left is to block the thread
+        let token = match self.handler.sleepers.insert((coro, None)) {
+            Ok(token) => token,
+            Err(..) => {
+                error!("Too many sleeping coroutines");
+                return thread::sleep(dur);
+            }
+        };
+
+        match self.event_loop.timeout_ms(Timer::Sleep(token), delay) {
+            Ok(timer) => self.handler.sleepers[token].1 = Some(timer),
+            Err(err) => {
+                error!("Failed to register timer: {:?}", err);
+                self.handler.sleepers.remove(token);
+                return thread::sleep(dur);
+            }
+        }
+
+        debug!("sleep: Blocked {} for {} ms", unsafe { (&*coro.coro_ptr).info() }, delay);
+
+        let handle = self.handle.clone();
+        let waker = move|| {
+            if let Err(err) = handle.sender.send(Message::CancelSleep(token, coro)) {
+                error!("Failed to abort a sleeping coroutine: {:?}", err);
+            }
+        };
+
+        let cancel = unsafe { (&*coro.coro_ptr).cancel() };
+        let blocked = match cancel {
+            Some(cancel) => cancel.with_waker(&waker, || Scheduler::block()).is_some(),
+            None => {
+                Scheduler::block();
+                true
+            }
+        };
+
+        if !blocked {
+            // Aborted before being blocked
+            if let Some((_, Some(timer))) = self.handler.sleepers.remove(token) {
+                self.event_loop.clear_timeout(timer);
+            }
+        }
+
+        // May be resumed by another processor
+        Processor::current().check_aborted();
+    }
+
     /// Yield the current running coroutine with specified result
     pub fn yield_with(&mut self, r: coroutine::Result<State>) {
         match self.cur_running.take() {
@@ -188,113 +703,388 @@ impl Processor {
 }
 
 const MAX_TOKEN_NUM: usize = 102400;
-impl IoHandler {
-    fn new() -> IoHandler {
-        IoHandler {
-            slabs: Slab::new(MAX_TOKEN_NUM),
-        }
-    }
-}
 
+// Linux EPoll needs to explicit EPOLL_CTL_DEL the fd, kqueue removes oneshot events by itself
 #[cfg(any(target_os = "linux",
           target_os = "android"))]
+const DEREGISTER_ON_READY: bool = true;
+
+#[cfg(not(any(target_os = "linux",
+              target_os = "android")))]
+const DEREGISTER_ON_READY: bool = false;
+
 impl Processor {
     /// Register and wait I/O
     pub fn wait_event<E: Evented + AsRawFd>(&mut self, fd: &E, interest: EventSet) -> io::Result<()> {
-        let token = self.handler.slabs.insert((Processor::current().running().unwrap(),
-                                               From::from(fd.as_raw_fd()))).unwrap();
-        try!(self.event_loop.register_opt(fd, token, interest,
-                                          PollOpt::edge()|PollOpt::oneshot()));
+        self.wait_event_timeout(fd, interest, None)
+    }
 
-        debug!("wait_event: Blocked current Coroutine ...; token={:?}", token);
-        Scheduler::block();
-        debug!("wait_event: Waked up; token={:?}", token);
+    /// Register and wait I/O, fails with `TimedOut` if nothing happened within `timeout`
+    pub fn wait_event_timeout<E: Evented + AsRawFd>(&mut self, fd: &E, interest: EventSet,
+                                                    timeout: Option<Duration>) -> io::Result<()> {
+        if self.scheduler.is_shutdown() {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "scheduler is shutting down"));
+        }
 
-        Ok(())
+        let coro = Processor::current().running().unwrap();
+        if unsafe { (&*coro.coro_ptr).is_aborted() } {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "coroutine is aborted"));
+        }
+
+        let token = match self.handler.slabs.insert((coro, From::from(fd.as_raw_fd()), None)) {
+            Ok(token) => token,
+            Err((_, fd, _)) => {
+                mem::forget(fd);
+                return Err(io::Error::new(io::ErrorKind::Other, "too many coroutines waiting for I/O"));
+            }
+        };
+
+        if let Err(err) = self.event_loop.register_opt(fd, token, interest,
+                                                       PollOpt::edge()|PollOpt::oneshot()) {
+            self.handler.forget(token);
+            return Err(err);
+        }
+
+        if let Some(dur) = timeout {
+            match self.event_loop.timeout_ms(Timer::Io(token), duration_to_ms(&dur)) {
+                Ok(timer) => self.handler.slabs[token].2 = Some(timer),
+                Err(err) => {
+                    error!("Failed to register timer: {:?}", err);
+                    let _ = self.event_loop.deregister(fd);
+                    self.handler.forget(token);
+                    return Err(io::Error::new(io::ErrorKind::Other, "too many timers"));
+                }
+            }
+        }
+
+        debug!("wait_event: Blocked {}; token={:?}", unsafe { (&*coro.coro_ptr).info() }, token);
+
+        let status = unsafe { (&*coro.coro_ptr).status() };
+        status.set_wait_event(Some((fd.as_raw_fd(), interest)));
+
+        let handle = self.handle.clone();
+        let waker = move|| {
+            if let Err(err) = handle.sender.send(Message::CancelIo(token, coro)) {
+                error!("Failed to abort a coroutine waiting for I/O: {:?}", err);
+            }
+        };
+
+        match unsafe { (&*coro.coro_ptr).cancel() } {
+            Some(cancel) => {
+                if cancel.with_waker(&waker, || Scheduler::block()).is_none() {
+                    // Aborted before being blocked
+                    self.handler.unregister(&mut self.event_loop, token);
+                    status.set_wait_event(None);
+                    return Err(io::Error::new(io::ErrorKind::Interrupted, "coroutine is aborted"));
+                }
+            },
+            None => Scheduler::block(),
+        }
+
+        status.set_wait_event(None);
+        debug!("wait_event: Waked up {}; token={:?}", unsafe { (&*coro.coro_ptr).info() }, token);
+
+        match unsafe { (&mut *coro.coro_ptr).take_wakeup_error() } {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
     }
 }
 
-#[cfg(any(target_os = "linux",
-          target_os = "android"))]
 struct IoHandler {
-    slabs: Slab<(CoroutineRefMut, Io)>,
+    slabs: Slab<(CoroutineRefMut, Io, Option<Timeout>)>,
+    sleepers: Slab<(CoroutineRefMut, Option<Timeout>)>,
+    handle: Arc<ProcessorHandle>,
+}
+
+impl IoHandler {
+    fn new(handle: Arc<ProcessorHandle>) -> IoHandler {
+        IoHandler {
+            slabs: Slab::new(MAX_TOKEN_NUM),
+            sleepers: Slab::new(MAX_TOKEN_NUM),
+            handle: handle,
+        }
+    }
+
+    /// Update the statistics of the waiting coroutines
+    fn publish(&self) {
+        let counters = &self.handle.counters;
+        counters.io_waiters.store(self.slabs.count(), Ordering::Relaxed);
+        counters.sleepers.store(self.sleepers.count(), Ordering::Relaxed);
+    }
+
+    /// Whether any coroutine is waiting for I/O events or timers
+    fn has_waiters(&self) -> bool {
+        self.slabs.count() != 0 || self.sleepers.count() != 0
+    }
+
+    /// Remove a registration and its timer, the fd is left open
+    fn unregister(&mut self, event_loop: &mut EventLoop<IoHandler>, token: Token)
+            -> Option<CoroutineRefMut> {
+        self.slabs.remove(token).map(|(hdl, fd, timer)| {
+            let _ = event_loop.deregister(&fd);
+            mem::forget(fd);
+
+            if let Some(timer) = timer {
+                event_loop.clear_timeout(timer);
+            }
+
+            hdl
+        })
+    }
+
+    /// Remove a registration without closing the fd, which is owned by the caller
+    fn forget(&mut self, token: Token) -> Option<CoroutineRefMut> {
+        self.slabs.remove(token).map(|(hdl, fd, _)| {
+            mem::forget(fd);
+            hdl
+        })
+    }
+
+    /// Wake up all the coroutines waiting for I/O with an `Interrupted` error
+    fn interrupt(&mut self, event_loop: &mut EventLoop<IoHandler>) {
+        // The slab can't tell which tokens are in use
+        for idx in 0..MAX_TOKEN_NUM {
+            if self.slabs.count() == 0 {
+                break;
+            }
+
+            if let Some(hdl) = self.unregister(event_loop, Token(idx)) {
+                unsafe {
+                    (&mut *hdl.coro_ptr).set_wakeup_error(
+                        io::Error::new(io::ErrorKind::Interrupted, "scheduler is shutting down"));
+                }
+                Scheduler::ready(hdl);
+            }
+        }
+    }
+
+    /// Remove all the coroutines waiting for I/O events or timers
+    fn take_waiters(&mut self, event_loop: &mut EventLoop<IoHandler>) -> Vec<CoroutineRefMut> {
+        let mut waiters = Vec::new();
+
+        // The slab can't tell which tokens are in use
+        for idx in 0..MAX_TOKEN_NUM {
+            if self.slabs.count() == 0 {
+                break;
+            }
+
+            if let Some(hdl) = self.unregister(event_loop, Token(idx)) {
+                waiters.push(hdl);
+            }
+        }
+
+        self.sleepers.retain(|&(hdl, timer)| {
+            if let Some(timer) = timer {
+                event_loop.clear_timeout(timer);
+            }
+            waiters.push(hdl);
+            false
+        });
+
+        waiters
+    }
 }
 
-#[cfg(any(target_os = "linux",
-          target_os = "android"))]
 impl Handler for IoHandler {
-    type Timeout = ();
-    type Message = ();
+    type Timeout = Timer;
+    type Message = Message;
 
     fn ready(&mut self, event_loop: &mut EventLoop<Self>, token: Token, events: EventSet) {
-        debug!("Got {:?} for {:?}", events, token);
-
         match self.slabs.remove(token) {
-            Some((hdl, fd)) => {
-                // Linux EPoll needs to explicit EPOLL_CTL_DEL the fd
-                event_loop.deregister(&fd).unwrap();
+            Some((hdl, fd, timer)) => {
+                debug!("Got {:?} for {:?}, waking up {}", events, token,
+                       unsafe { (&*hdl.coro_ptr).info() });
+                Counters::incr(&self.handle.counters.io_wakeups);
+
+                if DEREGISTER_ON_READY {
+                    event_loop.deregister(&fd).unwrap();
+                }
                 mem::forget(fd);
+
+                if let Some(timer) = timer {
+                    event_loop.clear_timeout(timer);
+                }
+
                 Scheduler::ready(hdl);
             },
             None => {
-                warn!("No coroutine is waiting on readable {:?}", token);
+                warn!("Got {:?} for {:?}, but no coroutine is waiting on it", events, token);
             }
         }
     }
-}
 
-#[cfg(any(target_os = "macos",
-          target_os = "freebsd",
-          target_os = "dragonfly",
-          target_os = "ios",
-          target_os = "bitrig",
-          target_os = "openbsd"))]
-impl Processor {
-    /// Register and wait I/O
-    pub fn wait_event<E: Evented>(&mut self, fd: &E, interest: EventSet) -> io::Result<()> {
-        let token = self.handler.slabs.insert(Processor::current().running().unwrap()).unwrap();
-        try!(self.event_loop.register_opt(fd, token, interest,
-                                          PollOpt::edge()|PollOpt::oneshot()));
+    fn notify(&mut self, event_loop: &mut EventLoop<Self>, msg: Message) {
+        match msg {
+            Message::Wakeup => debug!("Processor is waked up for new works"),
+            Message::CancelIo(token, coro) => {
+                // The token may have been reused if the coroutine has been waked up already
+                match self.slabs.get(token) {
+                    Some(&(hdl, _, _)) if hdl.coro_ptr == coro.coro_ptr => {},
+                    _ => return,
+                }
 
-        debug!("wait_event: Blocked current Coroutine ...; token={:?}", token);
-        // Coroutine::block();
-        Scheduler::block();
-        debug!("wait_event: Waked up; token={:?}", token);
+                debug!("Aborted the coroutine waiting for {:?}", token);
+                if let Some(hdl) = self.unregister(event_loop, token) {
+                    unsafe {
+                        (&mut *hdl.coro_ptr).set_wakeup_error(
+                            io::Error::new(io::ErrorKind::Interrupted, "coroutine is aborted"));
+                    }
+                    Scheduler::ready(hdl);
+                }
+            },
+            Message::CancelSleep(token, coro) => {
+                match self.sleepers.get(token) {
+                    Some(&(hdl, _)) if hdl.coro_ptr == coro.coro_ptr => {},
+                    _ => return,
+                }
 
-        Ok(())
+                debug!("Aborted the coroutine sleeping with {:?}", token);
+                if let Some((hdl, timer)) = self.sleepers.remove(token) {
+                    if let Some(timer) = timer {
+                        event_loop.clear_timeout(timer);
+                    }
+                    Scheduler::ready(hdl);
+                }
+            },
+        }
     }
-}
 
-#[cfg(any(target_os = "macos",
-          target_os = "freebsd",
-          target_os = "dragonfly",
-          target_os = "ios",
-          target_os = "bitrig",
-          target_os = "openbsd"))]
-struct IoHandler {
-    slabs: Slab<CoroutineRefMut>,
+    fn timeout(&mut self, event_loop: &mut EventLoop<Self>, timer: Timer) {
+        match timer {
+            Timer::Sleep(token) => {
+                debug!("Sleep timeout, waking up the coroutine");
+                if let Some((hdl, _)) = self.sleepers.remove(token) {
+                    Scheduler::ready(hdl);
+                }
+            },
+            Timer::Io(token) => {
+                debug!("I/O timeout for {:?}", token);
+
+                if let Some((hdl, fd, _)) = self.slabs.remove(token) {
+                    let _ = event_loop.deregister(&fd);
+                    mem::forget(fd);
+
+                    unsafe {
+                        (&mut *hdl.coro_ptr).set_wakeup_error(
+                            io::Error::new(io::ErrorKind::TimedOut, "timed out"));
+                    }
+                    Scheduler::ready(hdl);
+                }
+            },
+            Timer::Drain => debug!("Drain period is over"),
+        }
+    }
 }
 
-#[cfg(any(target_os = "macos",
-          target_os = "freebsd",
-          target_os = "dragonfly",
-          target_os = "ios",
-          target_os = "bitrig",
-          target_os = "openbsd"))]
-impl Handler for IoHandler {
-    type Timeout = ();
-    type Message = ();
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::{Duration, Instant};
 
-    fn ready(&mut self, _: &mut EventLoop<Self>, token: Token, events: EventSet) {
-        debug!("Got {:?} for {:?}", events, token);
+    use scheduler::Scheduler;
+    use runtime::{Runtime, Config};
+    use options::{Options, Priority};
 
-        match self.slabs.remove(token) {
-            Some(hdl) => {
-                Scheduler::ready(hdl);
-            },
-            None => {
-                warn!("No coroutine is waiting on readable {:?}", token);
-            }
+    #[test]
+    fn test_sleep() {
+        let runtime = Runtime::new(Config::new());
+
+        let start = Instant::now();
+
+        for _ in 0..10 {
+            runtime.spawn(|| {
+                let start = Instant::now();
+                Scheduler::sleep(Duration::from_millis(100));
+                assert!(start.elapsed() >= Duration::from_millis(100));
+            });
         }
+
+        runtime.run();
+
+        // All coroutines slept at the same time on one thread
+        assert!(start.elapsed() < Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_priority() {
+        let runtime = Runtime::new(Config::new());
+
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let order_cloned = order.clone();
+        runtime.spawn(move|| {
+            for &(name, priority) in &[("low", Priority::Low), ("high", Priority::High)] {
+                for _ in 0..3 {
+                    let order = order_cloned.clone();
+                    Scheduler::spawn_opts(move|| {
+                        // The high ones run immediately and the low ones are queued, all of
+                        // them are queued by priority after this yield
+                        Scheduler::sched();
+                        order.lock().unwrap().push(name);
+                    }, Options::new().priority(priority));
+                }
+            }
+        });
+
+        // The low priority coroutine still runs while a high priority one keeps yielding
+        let hdl = runtime.spawn(|| {
+            let done = Arc::new(AtomicBool::new(false));
+            let done_cloned = done.clone();
+
+            let busy = Scheduler::spawn_opts(move|| {
+                let mut yields = 0;
+                while !done.load(Ordering::SeqCst) {
+                    Scheduler::sched();
+                    yields += 1;
+                }
+                yields
+            }, Options::new().priority(Priority::High));
+
+            Scheduler::spawn_opts(move|| {
+                Scheduler::sched();
+                done_cloned.store(true, Ordering::SeqCst);
+            }, Options::new().priority(Priority::Low));
+
+            busy.join().unwrap()
+        });
+
+        runtime.run();
+
+        assert_eq!(*order.lock().unwrap(), ["high", "high", "high", "low", "low", "low"]);
+        assert!(hdl.join().unwrap() < 100);
+    }
+
+    #[test]
+    fn test_priority_without_normal() {
+        let runtime = Runtime::new(Config::new());
+
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let order_cloned = order.clone();
+        runtime.spawn_opts(move|| {
+            for _ in 0..3 {
+                let order = order_cloned.clone();
+                // Queued, it must not run ahead of this high priority coroutine
+                Scheduler::spawn_opts(move|| {
+                    order.lock().unwrap().push("low");
+                }, Options::new().priority(Priority::Low));
+            }
+            order_cloned.lock().unwrap().push("parent");
+
+            // Yield across the ticks preferring the normal queue, which is empty
+            for _ in 0..2 {
+                let order = order_cloned.clone();
+                Scheduler::spawn_opts(move|| {
+                    for _ in 0..5 {
+                        Scheduler::sched();
+                    }
+                    order.lock().unwrap().push("high");
+                }, Options::new().priority(Priority::High));
+            }
+        }, Options::new().priority(Priority::High));
+
+        runtime.run();
+
+        assert_eq!(*order.lock().unwrap(), ["parent", "high", "high", "low", "low", "low"]);
     }
 }
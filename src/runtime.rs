@@ -0,0 +1,430 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Coroutine runtime with its own scheduler
+
+use std::sync::Arc;
+use std::default::Default;
+use std::time::Duration;
+
+use scheduler::Scheduler;
+use options::Options;
+use stack_pool::StackPool;
+use join_handle::JoinHandle;
+
+/// Runtime configuration
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub threads: usize,
+    pub global_queue_size: usize,
+    pub local_queue_size: usize,
+    pub drain_timeout: Duration,
+    pub max_cached_stacks: usize,
+    pub madvise_stacks: bool,
+    pub stack_pool: Option<Arc<StackPool>>,
+    pub blocking_threads: usize,
+    pub cpu_affinity: bool,
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            threads: 1,
+            global_queue_size: 0x1000,
+            local_queue_size: 0x100,
+            drain_timeout: Duration::from_secs(5),
+            max_cached_stacks: 1024,
+            madvise_stacks: false,
+            stack_pool: None,
+            blocking_threads: 16,
+            cpu_affinity: false,
+        }
+    }
+
+    /// Number of worker threads, including the thread calling `Runtime::run`
+    pub fn threads(mut self, threads: usize) -> Config {
+        self.threads = threads;
+        self
+    }
+
+    /// Capacity of the lock-free part of the global queue
+    pub fn global_queue_size(mut self, size: usize) -> Config {
+        self.global_queue_size = size;
+        self
+    }
+
+    /// Capacity of the local queue of every worker thread
+    pub fn local_queue_size(mut self, size: usize) -> Config {
+        self.local_queue_size = size;
+        self
+    }
+
+    /// How long the coroutines may keep running after `Scheduler::shutdown`
+    pub fn drain_timeout(mut self, timeout: Duration) -> Config {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Maximum number of stacks kept in the stack pool after their coroutines finished
+    pub fn max_cached_stacks(mut self, max: usize) -> Config {
+        self.max_cached_stacks = max;
+        self
+    }
+
+    /// Give the memory of cached stacks back to the OS with `madvise(MADV_DONTNEED)`
+    pub fn madvise_stacks(mut self, madvise: bool) -> Config {
+        self.madvise_stacks = madvise;
+        self
+    }
+
+    /// Use an existing stack pool instead of creating one, `max_cached_stacks` and
+    /// `madvise_stacks` are ignored
+    pub fn stack_pool(mut self, pool: Arc<StackPool>) -> Config {
+        self.stack_pool = Some(pool);
+        self
+    }
+
+    /// Maximum number of threads running the works of `spawn_blocking`
+    pub fn blocking_threads(mut self, threads: usize) -> Config {
+        self.blocking_threads = threads;
+        self
+    }
+
+    /// Pin the thread of the processor `i` to the CPU core `i % cores` with
+    /// `sched_setaffinity`, including the thread calling `Runtime::run`, which gets its
+    /// previous affinity back when `Runtime::run` returns
+    pub fn cpu_affinity(mut self, pin: bool) -> Config {
+        self.cpu_affinity = pin;
+        self
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config::new()
+    }
+}
+
+/// Coroutine runtime which owns its queues, work count and processors
+///
+/// Coroutines spawned inside the runtime with `Scheduler::spawn` belong to the same runtime.
+pub struct Runtime {
+    threads: usize,
+    scheduler: Arc<Scheduler>,
+}
+
+impl Runtime {
+    pub fn new(config: Config) -> Runtime {
+        Runtime {
+            threads: config.threads,
+            scheduler: Arc::new(Scheduler::new(&config)),
+        }
+    }
+
+    /// Spawn a new coroutine
+    pub fn spawn<F, T>(&self, f: F) -> JoinHandle<T>
+        where F: FnOnce() -> T + Send + 'static,
+              T: Send + 'static
+    {
+        self.spawn_opts(f, Default::default())
+    }
+
+    /// Spawn a new coroutine with options
+    pub fn spawn_opts<F, T>(&self, f: F, opts: Options) -> JoinHandle<T>
+        where F: FnOnce() -> T + Send + 'static,
+              T: Send + 'static
+    {
+        spawn_in(&self.scheduler, f, opts)
+    }
+
+    /// Run the coroutines in the configured number of threads, until all of them are finished
+    ///
+    /// Must not be called inside a coroutine.
+    pub fn run(&self) {
+        Scheduler::run_threads(&self.scheduler, self.threads);
+    }
+
+    /// Get a handle for spawning coroutines from the other threads
+    pub fn handle(&self) -> Handle {
+        Handle {
+            scheduler: self.scheduler.clone(),
+        }
+    }
+
+    /// The scheduler of this runtime
+    pub fn scheduler(&self) -> &Scheduler {
+        &self.scheduler
+    }
+}
+
+/// Handle for spawning coroutines into a `Runtime` from any thread
+///
+/// Coroutines spawned after `Runtime::run` has returned will run in the next call of it.
+#[derive(Clone)]
+pub struct Handle {
+    scheduler: Arc<Scheduler>,
+}
+
+impl Handle {
+    /// Spawn a new coroutine in the runtime
+    pub fn spawn<F, T>(&self, f: F) -> JoinHandle<T>
+        where F: FnOnce() -> T + Send + 'static,
+              T: Send + 'static
+    {
+        self.spawn_opts(f, Default::default())
+    }
+
+    /// Spawn a new coroutine in the runtime with options
+    pub fn spawn_opts<F, T>(&self, f: F, opts: Options) -> JoinHandle<T>
+        where F: FnOnce() -> T + Send + 'static,
+              T: Send + 'static
+    {
+        spawn_in(&self.scheduler, f, opts)
+    }
+
+    /// Shut down the runtime, see `Scheduler::shutdown`
+    pub fn shutdown(&self) {
+        self.scheduler.shutdown();
+    }
+}
+
+fn spawn_in<F, T>(scheduler: &Arc<Scheduler>, f: F, opts: Options) -> JoinHandle<T>
+    where F: FnOnce() -> T + Send + 'static,
+          T: Send + 'static
+{
+    let (coro, handle) = Scheduler::new_coroutine(scheduler, f, opts);
+    if let Some(coro) = coro {
+        Scheduler::ready(coro);
+    }
+    handle
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::thread;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    use scheduler::Scheduler;
+    use net::tcp::TcpListener;
+    use sync::Mutex;
+    use sync::mpsc::channel;
+    use super::{Runtime, Config};
+
+    #[test]
+    fn test_runtime_isolated() {
+        let counters: Vec<_> = (0..2).map(|_| Arc::new(AtomicUsize::new(0))).collect();
+
+        let threads: Vec<_> = counters.iter().cloned().map(|counter| {
+            thread::spawn(move|| {
+                let runtime = Runtime::new(Config::new().threads(2));
+                for _ in 0..10 {
+                    let counter = counter.clone();
+                    runtime.spawn(move|| {
+                        // Spawned inside the runtime, so it belongs to the same runtime
+                        Scheduler::spawn(move|| {
+                            counter.fetch_add(1, Ordering::SeqCst);
+                        });
+                    });
+                }
+
+                runtime.run();
+                assert_eq!(runtime.scheduler().work_count(), 0);
+            })
+        }).collect();
+
+        for t in threads.into_iter() {
+            t.join().unwrap();
+        }
+
+        for counter in counters.iter() {
+            assert_eq!(counter.load(Ordering::SeqCst), 10);
+        }
+    }
+
+    #[test]
+    fn test_runtime_handle() {
+        let runtime = Runtime::new(Config::new());
+        let handle = runtime.handle();
+
+        let hdl = thread::spawn(move|| handle.spawn(|| 1 + 1)).join().unwrap();
+        runtime.run();
+
+        assert_eq!(hdl.join().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_runtime_unpark() {
+        let runtime = Runtime::new(Config::new().threads(2));
+        let handle = runtime.handle();
+
+        // Keeps the work count above zero, so the idle processors park instead of quitting
+        let (tx, rx) = channel();
+        let done = Arc::new(AtomicBool::new(false));
+        let receiver = {
+            let done = done.clone();
+            runtime.spawn(move|| {
+                rx.recv().unwrap();
+                done.store(true, Ordering::SeqCst);
+            })
+        };
+
+        let spawner = thread::spawn(move|| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while handle.scheduler.idle_processors() != 2 && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(1));
+            }
+            let parked = handle.scheduler.idle_processors() == 2;
+
+            handle.spawn(move|| tx.send(()).unwrap());
+
+            // A lost wakeup leaves the processors parked forever
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while !done.load(Ordering::SeqCst) && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(1));
+            }
+            if !done.load(Ordering::SeqCst) {
+                handle.shutdown();
+            }
+
+            parked
+        });
+
+        runtime.run();
+
+        assert!(spawner.join().unwrap());
+        assert!(receiver.join().is_ok());
+    }
+
+    #[test]
+    fn test_runtime_shutdown() {
+        let runtime = Runtime::new(Config::new().threads(2).drain_timeout(Duration::from_millis(100)));
+        let handle = runtime.handle();
+
+        let hdl = runtime.spawn(|| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let mut errors = Vec::new();
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(..) => {},
+                    Err(err) => {
+                        errors.push(err.kind());
+                        break;
+                    }
+                }
+            }
+            errors
+        });
+
+        let shutdown = thread::spawn(move|| {
+            thread::sleep(Duration::from_millis(100));
+            handle.shutdown();
+
+            // No new works are accepted
+            handle.spawn(|| {}).join().is_err()
+        });
+
+        runtime.run();
+
+        assert!(shutdown.join().unwrap());
+        assert_eq!(hdl.join().unwrap(), vec![io::ErrorKind::Interrupted]);
+    }
+
+    #[test]
+    fn test_runtime_abandon() {
+        let runtime = Runtime::new(Config::new().drain_timeout(Duration::from_millis(50)));
+        let handle = runtime.handle();
+
+        let sleeper = runtime.spawn(|| Scheduler::sleep(Duration::from_secs(10)));
+        let (_tx, rx) = channel::<()>();
+        let receiver = runtime.spawn(move|| rx.recv());
+
+        let shutdown = thread::spawn(move|| {
+            thread::sleep(Duration::from_millis(50));
+            handle.shutdown();
+        });
+
+        let start = Instant::now();
+        runtime.run();
+        shutdown.join().unwrap();
+
+        // Neither of them finished in the drain period
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(sleeper.join().is_err());
+        assert!(receiver.join().is_err());
+        assert_eq!(runtime.scheduler().work_count(), 0);
+    }
+
+    #[test]
+    fn test_runtime_free_abandoned() {
+        let runtime = Runtime::new(Config::new().drain_timeout(Duration::from_millis(10)));
+        let handle = runtime.handle();
+
+        let mutex = Arc::new(Mutex::new(()));
+        let guard = mutex.lock().unwrap();
+        let mutex_cloned = mutex.clone();
+        let locker = runtime.spawn(move|| {
+            let _guard = mutex_cloned.lock().unwrap();
+        });
+        let (tx, rx) = channel::<()>();
+        let receiver = runtime.spawn(move|| rx.recv());
+
+        let shutdown = thread::spawn(move|| {
+            thread::sleep(Duration::from_millis(50));
+            handle.shutdown();
+        });
+        runtime.run();
+        shutdown.join().unwrap();
+
+        // Both are still in the wait lists
+        let stack_pool = runtime.scheduler().stack_pool().clone();
+        assert_eq!(stack_pool.stats().in_use, 2);
+
+        drop(guard);
+        drop(tx);
+        assert_eq!(stack_pool.stats().in_use, 0);
+        assert!(locker.join().is_err());
+        assert!(receiver.join().is_err());
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn test_runtime_restore_affinity() {
+        use affinity;
+
+        let before = affinity::mask().unwrap();
+        // The processor 0 keeps the affinity if it can't be pinned to the CPU 0
+        let pinned = match affinity::set_cpu(0) {
+            Ok(..) => affinity::mask().unwrap(),
+            Err(..) => before.clone(),
+        };
+        affinity::set_mask(&before).unwrap();
+
+        let runtime = Runtime::new(Config::new().cpu_affinity(true));
+        let hdl = runtime.spawn(|| affinity::mask().unwrap());
+        runtime.run();
+
+        assert_eq!(hdl.join().unwrap(), pinned);
+        assert_eq!(affinity::mask().unwrap(), before);
+    }
+}
@@ -19,22 +19,32 @@
 // IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
 // CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
-//! Global coroutine scheduler
+//! Coroutine scheduler
 
 use std::thread;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::collections::{HashMap, VecDeque};
 use std::default::Default;
+use std::time::{Duration, Instant};
+use std::mem;
 
+use mio::Sender;
 use mio::util::BoundedQueue;
 
-use processor::Processor;
+use processor::{Processor, Message};
 
-use coroutine::Coroutine;
+use coroutine::{Coroutine, CoroutineInfo};
 use options::Options;
+use runtime::Config;
+use stats::{Stats, Counters};
+use stack_pool::StackPool;
+use blocking::BlockingPool;
+use dump::{self, CoroutineDump};
+use join_handle::{self, JoinHandle, Join};
 
 lazy_static! {
-    static ref SCHEDULER: Scheduler = Scheduler::new();
+    static ref SCHEDULER: Arc<Scheduler> = Arc::new(Scheduler::new(&Config::new()));
 }
 
 #[doc(hidden)]
@@ -54,107 +64,819 @@ impl CoroutineRefMut {
 
 unsafe impl Send for CoroutineRefMut {}
 
+/// Queue for injecting coroutines into the scheduler
+///
+/// Pushes go to a lock-free bounded queue, and fall back to an unbounded list when it is
+/// full, so pushing never spins. Once anything is in the overflow list all pushes go there
+/// until it is drained, to keep the FIFO order.
+struct GlobalQueue {
+    len: AtomicUsize,
+    queue: BoundedQueue<CoroutineRefMut>,
+    overflow: Mutex<VecDeque<CoroutineRefMut>>,
+    overflow_len: AtomicUsize,
+    overflow_count: AtomicUsize,
+}
+
+impl GlobalQueue {
+    fn with_capacity(capacity: usize) -> GlobalQueue {
+        GlobalQueue {
+            len: AtomicUsize::new(0),
+            queue: BoundedQueue::with_capacity(capacity),
+            overflow: Mutex::new(VecDeque::new()),
+            overflow_len: AtomicUsize::new(0),
+            overflow_count: AtomicUsize::new(0),
+        }
+    }
+
+    fn push(&self, mut coro: CoroutineRefMut) {
+        // Counted before being pushed, so a concurrent pop won't make it negative
+        self.len.fetch_add(1, Ordering::Relaxed);
+
+        if self.overflow_len.load(Ordering::SeqCst) == 0 {
+            match self.queue.push(coro) {
+                Ok(..) => return,
+                Err(h) => coro = h,
+            }
+        }
+
+        let mut overflow = self.overflow.lock().unwrap();
+        overflow.push_back(coro);
+        self.overflow_len.store(overflow.len(), Ordering::SeqCst);
+        self.overflow_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn pop(&self) -> Option<CoroutineRefMut> {
+        let hdl = self.queue.pop().or_else(|| {
+            if self.overflow_len.load(Ordering::SeqCst) == 0 {
+                return None;
+            }
+
+            let mut overflow = self.overflow.lock().unwrap();
+            let hdl = overflow.pop_front();
+            self.overflow_len.store(overflow.len(), Ordering::SeqCst);
+            hdl
+        });
+
+        if hdl.is_some() {
+            self.len.fetch_sub(1, Ordering::Relaxed);
+        }
+        hdl
+    }
+}
+
+// Number of the locks of the coroutine registry
+const REGISTRY_STRIPES: usize = 32;
+
+/// Live coroutines of a scheduler, for `dump` and for abandoning them at shutdown
+///
+/// A coroutine is kept in the stripe of the processor which spawned it, so the processors
+/// don't contend on one lock when spawning and finishing coroutines.
+struct Registry {
+    stripes: Vec<Mutex<HashMap<usize, CoroutineRefMut>>>,
+}
+
+impl Registry {
+    fn new() -> Registry {
+        Registry {
+            stripes: (0..REGISTRY_STRIPES).map(|_| Mutex::new(HashMap::new())).collect(),
+        }
+    }
+
+    /// The stripe for a coroutine spawned by the current thread
+    fn stripe(id: usize) -> usize {
+        Processor::try_current().map_or(id, |p| p.id()) % REGISTRY_STRIPES
+    }
+
+    fn insert(&self, stripe: usize, id: usize, coro: CoroutineRefMut) {
+        self.stripes[stripe].lock().unwrap().insert(id, coro);
+    }
+
+    fn remove(&self, stripe: usize, id: usize) {
+        self.stripes[stripe].lock().unwrap().remove(&id);
+    }
+
+    fn dump(&self) -> Vec<CoroutineDump> {
+        let mut dump = Vec::new();
+        for stripe in self.stripes.iter() {
+            // A coroutine is removed before being freed
+            let coroutines = stripe.lock().unwrap();
+            dump.extend(coroutines.values().map(|coro| unsafe {
+                (&*coro.coro_ptr).status().dump()
+            }));
+        }
+        dump
+    }
+
+    fn take_all(&self) -> Vec<CoroutineRefMut> {
+        let mut all = Vec::new();
+        for stripe in self.stripes.iter() {
+            let coroutines = mem::replace(&mut *stripe.lock().unwrap(), HashMap::new());
+            all.extend(coroutines.into_iter().map(|(_, coro)| coro));
+        }
+        all
+    }
+}
+
+#[doc(hidden)]
+/// The parts of a processor which are shared with the other threads
+pub struct ProcessorHandle {
+    pub id: usize,
+    /// Local queues indexed by `Priority`
+    pub queues: Vec<Arc<BoundedQueue<CoroutineRefMut>>>,
+    pub sender: Sender<Message>,
+    pub idle: AtomicBool,
+    pub counters: Counters,
+    /// Coroutines pinned to this processor, which can't be stolen
+    pub inbox: Mutex<VecDeque<CoroutineRefMut>>,
+    pub inbox_len: AtomicUsize,
+}
+
+impl ProcessorHandle {
+    pub fn push_inbox(&self, coro: CoroutineRefMut) {
+        let mut inbox = self.inbox.lock().unwrap();
+        inbox.push_back(coro);
+        self.inbox_len.store(inbox.len(), Ordering::SeqCst);
+    }
+
+    pub fn pop_inbox(&self) -> Option<CoroutineRefMut> {
+        if self.inbox_len.load(Ordering::SeqCst) == 0 {
+            return None;
+        }
+
+        let mut inbox = self.inbox.lock().unwrap();
+        let coro = inbox.pop_front();
+        self.inbox_len.store(inbox.len(), Ordering::SeqCst);
+        coro
+    }
+}
+
 /// Coroutine scheduler
 pub struct Scheduler {
-    global_queue: Arc<BoundedQueue<CoroutineRefMut>>,
+    config: Config,
+    global_queue: GlobalQueue,
     work_counts: AtomicUsize,
+    processor_ids: AtomicUsize,
+    threads: AtomicUsize,
+    processors: RwLock<Vec<Arc<ProcessorHandle>>>,
+    idle_processors: AtomicUsize,
+    shutdown: AtomicBool,
+    cpu_affinity: AtomicBool,
+    drain_deadline: Mutex<Option<Instant>>,
+    coroutines: Registry,
+    stack_pool: Arc<StackPool>,
+    blocking_pool: BlockingPool,
 }
 
 unsafe impl Send for Scheduler {}
 unsafe impl Sync for Scheduler {}
 
-const GLOBAL_QUEUE_SIZE: usize = 0x1000;
-
 impl Scheduler {
-    fn new() -> Scheduler {
+    #[doc(hidden)]
+    pub fn new(config: &Config) -> Scheduler {
         Scheduler {
-            global_queue: Arc::new(BoundedQueue::with_capacity(GLOBAL_QUEUE_SIZE)),
+            config: config.clone(),
+            global_queue: GlobalQueue::with_capacity(config.global_queue_size),
             work_counts: AtomicUsize::new(0),
+            processor_ids: AtomicUsize::new(0),
+            threads: AtomicUsize::new(config.threads),
+            processors: RwLock::new(Vec::new()),
+            idle_processors: AtomicUsize::new(0),
+            shutdown: AtomicBool::new(false),
+            cpu_affinity: AtomicBool::new(config.cpu_affinity),
+            drain_deadline: Mutex::new(None),
+            coroutines: Registry::new(),
+            stack_pool: match config.stack_pool {
+                Some(ref pool) => pool.clone(),
+                None => Arc::new(StackPool::new(config.max_cached_stacks, config.madvise_stacks)),
+            },
+            blocking_pool: BlockingPool::new(config.blocking_threads),
+        }
+    }
+
+    /// Get the Scheduler of the current thread
+    ///
+    /// It is the Scheduler of the `Runtime` in its worker threads, and the global Scheduler
+    /// in the other threads. It is owned, so it stays valid after the `Runtime` is dropped.
+    pub fn get() -> Arc<Scheduler> {
+        match Processor::try_current() {
+            Some(processor) => processor.scheduler().clone(),
+            None => SCHEDULER.clone(),
         }
     }
 
-    /// Get the global Scheduler
-    pub fn get() -> &'static Scheduler {
+    #[doc(hidden)]
+    /// The global Scheduler, which is used by `Scheduler::run`
+    pub fn global() -> &'static Arc<Scheduler> {
         &SCHEDULER
     }
 
+    #[doc(hidden)]
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Number of threads of the scheduler, the `n` of the last `Scheduler::run`, or
+    /// `Config::threads` until it is run
+    pub fn threads(&self) -> usize {
+        self.threads.load(Ordering::SeqCst)
+    }
+
+    #[doc(hidden)]
+    /// Whether the processors pin their threads to the CPU cores, see `Config::cpu_affinity`
+    pub fn cpu_affinity(&self) -> bool {
+        self.cpu_affinity.load(Ordering::Relaxed)
+    }
+
+    /// Pin the threads of the global scheduler to the CPU cores like `Config::cpu_affinity`,
+    /// takes effect on the processors started by the next `Scheduler::run`
+    pub fn set_cpu_affinity(pin: bool) {
+        SCHEDULER.cpu_affinity.store(pin, Ordering::Relaxed);
+    }
+
+    /// The pool which the stacks of the coroutines are taken from
+    pub fn stack_pool(&self) -> &Arc<StackPool> {
+        &self.stack_pool
+    }
+
     #[doc(hidden)]
     /// A coroutine is ready for schedule
-    pub fn ready(mut coro: CoroutineRefMut) {
-        loop {
-            match Scheduler::get().global_queue.push(coro) {
-                Ok(..) => return,
-                Err(h) => coro = h,
+    ///
+    /// It goes to the current processor if the processor belongs to the same scheduler
+    /// as the coroutine, otherwise to the global queue of the coroutine's scheduler.
+    pub fn ready(coro: CoroutineRefMut) {
+        if unsafe { (&*coro.coro_ptr).is_abandoned() } {
+            // Waked up from a wait list after its scheduler had shut down
+            return Scheduler::free_abandoned(coro);
+        }
+
+        let scheduler = unsafe { (&*coro.coro_ptr).scheduler() };
+
+        match Processor::try_current() {
+            Some(ref mut processor) if processor.belongs_to(scheduler) => processor.ready(coro),
+            _ => {
+                let coro = match unsafe { (&*coro.coro_ptr).pinned() } {
+                    Some(id) => match scheduler.push_pinned(coro, id) {
+                        Ok(..) => return,
+                        Err(coro) => coro,
+                    },
+                    None => coro,
+                };
+                scheduler.push_global(coro);
             }
         }
     }
 
     #[doc(hidden)]
-    /// Get the global work queue
-    pub fn get_queue(&self) -> Arc<BoundedQueue<CoroutineRefMut>> {
-        self.global_queue.clone()
+    /// Hand a pinned coroutine to the processor `id`, it is given back if that processor is
+    /// not scheduling
+    pub fn push_pinned(&self, coro: CoroutineRefMut, id: usize) -> Result<(), CoroutineRefMut> {
+        let processors = self.processors.read().unwrap();
+        match processors.iter().find(|p| p.id == id) {
+            Some(handle) => {
+                handle.push_inbox(coro);
+                self.wakeup(handle);
+                Ok(())
+            },
+            None => Err(coro),
+        }
+    }
+
+    #[doc(hidden)]
+    /// Push a coroutine into the global queue
+    pub fn push_global(&self, coro: CoroutineRefMut) {
+        self.global_queue.push(coro);
+        self.unpark_one();
+    }
+
+    #[doc(hidden)]
+    /// Pop a coroutine from the global queue
+    pub fn pop_global(&self) -> Option<CoroutineRefMut> {
+        self.global_queue.pop()
+    }
+
+    /// Number of times the global queue was full and coroutines went to the overflow list
+    pub fn overflow_count(&self) -> usize {
+        self.global_queue.overflow_count.load(Ordering::Relaxed)
+    }
+
+    #[doc(hidden)]
+    /// Allocate an unique ID for a new processor
+    pub fn next_processor_id(&self) -> usize {
+        self.processor_ids.fetch_add(1, Ordering::SeqCst)
+    }
+
+    #[doc(hidden)]
+    /// Make a processor visible to the others for stealing and waking up
+    pub fn register_processor(&self, handle: Arc<ProcessorHandle>) {
+        self.processors.write().unwrap().push(handle);
+    }
+
+    #[doc(hidden)]
+    /// Remove a processor which stops scheduling
+    pub fn unregister_processor(&self, id: usize) {
+        self.processors.write().unwrap().retain(|p| p.id != id);
+    }
+
+    #[doc(hidden)]
+    /// Steal a coroutine from the local queues of any other processor, higher priorities first
+    pub fn steal(&self, thief: usize) -> Option<CoroutineRefMut> {
+        let processors = self.processors.read().unwrap();
+        let len = processors.len();
+
+        // Start from different victims to avoid all thieves hitting the same queue
+        for i in 0..len {
+            let victim = &processors[(thief + i) % len];
+            if victim.id == thief {
+                continue;
+            }
+
+            if let Some(hdl) = victim.queues.iter().filter_map(|q| q.pop()).next() {
+                victim.counters.queue_depth.fetch_sub(1, Ordering::Relaxed);
+                debug!("Processor {} stole a coroutine from Processor {}", thief, victim.id);
+                return Some(hdl);
+            }
+        }
+
+        None
+    }
+
+    #[doc(hidden)]
+    /// Mark a processor as idle, it is going to block in its event loop
+    pub fn park(&self, handle: &ProcessorHandle) {
+        handle.idle.store(true, Ordering::SeqCst);
+        self.idle_processors.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[doc(hidden)]
+    /// Mark a processor as busy again
+    pub fn unpark(&self, handle: &ProcessorHandle) {
+        if handle.idle.compare_and_swap(true, false, Ordering::SeqCst) {
+            self.idle_processors.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    fn wakeup(&self, handle: &ProcessorHandle) -> bool {
+        if !handle.idle.compare_and_swap(true, false, Ordering::SeqCst) {
+            return false;
+        }
+
+        self.idle_processors.fetch_sub(1, Ordering::SeqCst);
+        if let Err(err) = handle.sender.send(Message::Wakeup) {
+            // The notify queue is full, so the processor is going to wake up anyway
+            debug!("Failed to wake up Processor {}: {:?}", handle.id, err);
+        }
+
+        true
+    }
+
+    #[doc(hidden)]
+    /// Number of processors blocked in their event loop
+    pub fn idle_processors(&self) -> usize {
+        self.idle_processors.load(Ordering::SeqCst)
+    }
+
+    #[doc(hidden)]
+    /// Wake up one idle processor, if any
+    pub fn unpark_one(&self) {
+        if self.idle_processors.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+
+        for handle in self.processors.read().unwrap().iter() {
+            if self.wakeup(handle) {
+                return;
+            }
+        }
+    }
+
+    #[doc(hidden)]
+    /// Wake up all idle processors
+    pub fn unpark_all(&self) {
+        for handle in self.processors.read().unwrap().iter() {
+            self.wakeup(handle);
+        }
+    }
+
+    /// Shut down the scheduler
+    ///
+    /// New coroutines won't be accepted anymore, and the coroutines waiting for I/O are waked
+    /// up with an `Interrupted` error. The other coroutines have `Config::drain_timeout` to
+    /// finish, then all the worker threads quit and `run` returns, the coroutines which are
+    /// still not finished are abandoned, joining them fails.
+    pub fn shutdown(&self) {
+        // The deadline must be visible before the flag
+        {
+            let mut deadline = self.drain_deadline.lock().unwrap();
+            if deadline.is_some() {
+                return;
+            }
+            *deadline = Some(Instant::now() + self.config.drain_timeout);
+        }
+
+        self.shutdown.store(true, Ordering::SeqCst);
+        self.unpark_all();
+    }
+
+    /// Whether `shutdown` has been called
+    pub fn is_shutdown(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    #[doc(hidden)]
+    /// Time when the processors stop after `shutdown` has been called
+    pub fn drain_deadline(&self) -> Option<Instant> {
+        *self.drain_deadline.lock().unwrap()
     }
 
     #[doc(hidden)]
     /// A coroutine is finished
     pub fn finished(coro: CoroutineRefMut) {
-        Scheduler::get().work_counts.fetch_sub(1, Ordering::SeqCst);
-
         let boxed = unsafe { Box::from_raw(coro.coro_ptr) };
+        let scheduler = boxed.scheduler().clone();
+        scheduler.coroutines.remove(boxed.registry_stripe(), boxed.info().id());
         drop(boxed);
+
+        if scheduler.work_counts.fetch_sub(1, Ordering::SeqCst) == 1 {
+            // All works are done, let the idle processors quit
+            scheduler.unpark_all();
+        }
+    }
+
+    #[doc(hidden)]
+    /// Abandon a coroutine which is not in any wait list, and free it
+    pub fn abandon(coro: CoroutineRefMut) {
+        unsafe { (&mut *coro.coro_ptr).abandon(); }
+        Scheduler::finished(coro);
+    }
+
+    #[doc(hidden)]
+    /// Free a coroutine which has been abandoned, once it is out of its wait list
+    pub fn free_abandoned(coro: CoroutineRefMut) {
+        drop(unsafe { Box::from_raw(coro.coro_ptr) });
+    }
+
+    /// Abandon the coroutines in the global queue, returns how many of them were abandoned
+    ///
+    /// The ones which have been abandoned already are waked up from their wait lists, they
+    /// are only freed.
+    fn abandon_queued(&self) -> usize {
+        let mut abandoned = 0;
+        while let Some(hdl) = self.pop_global() {
+            if unsafe { (&*hdl.coro_ptr).is_abandoned() } {
+                Scheduler::free_abandoned(hdl);
+            } else {
+                Scheduler::abandon(hdl);
+                abandoned += 1;
+            }
+        }
+        abandoned
     }
 
     /// Total work
     pub fn work_count(&self) -> usize {
-        Scheduler::get().work_counts.load(Ordering::SeqCst)
+        self.work_counts.load(Ordering::SeqCst)
+    }
+
+    /// List the live coroutines
+    pub fn dump(&self) -> Vec<CoroutineDump> {
+        let mut dump = self.coroutines.dump();
+        dump.sort_by(|a, b| a.id.cmp(&b.id));
+        dump
+    }
+
+    /// Take a snapshot of the statistics
+    pub fn stats(&self) -> Stats {
+        let processors = self.processors.read().unwrap();
+
+        Stats {
+            work_count: self.work_count(),
+            global_queue_depth: self.global_queue.len.load(Ordering::Relaxed),
+            overflow_count: self.overflow_count(),
+            stack_pool: self.stack_pool.stats(),
+            processors: processors.iter().map(|p| p.counters.snapshot(p.id)).collect(),
+        }
+    }
+
+    #[doc(hidden)]
+    /// Create a coroutine which belongs to `scheduler`, it is counted as a work until it
+    /// is finished
+    ///
+    /// No coroutine is created if the scheduler is shutting down, joining the handle
+    /// returns an error.
+    pub fn new_coroutine<F, T>(scheduler: &Arc<Scheduler>, f: F, opts: Options)
+        -> (Option<CoroutineRefMut>, JoinHandle<T>)
+        where F: FnOnce() -> T + 'static + Send,
+              T: Send + 'static
+    {
+        let (packet, cancel, handle) = join_handle::handle_pair();
+        if scheduler.is_shutdown() {
+            warn!("Scheduler is shutting down, the new coroutine is rejected");
+            packet.finish(Some(Box::new("the scheduler is shutting down")));
+            return (None, handle);
+        }
+
+        let their_packet = packet.clone();
+
+        let mut coro = Coroutine::spawn_opts(move|| their_packet.set(f()), opts, scheduler.clone());
+        coro.set_join(packet);
+        coro.set_cancel(cancel);
+
+        let id = coro.info().id();
+        let stripe = Registry::stripe(id);
+        coro.set_registry_stripe(stripe);
+        let coro = CoroutineRefMut::new(unsafe { mem::transmute(coro) });
+        scheduler.coroutines.insert(stripe, id, coro);
+
+        scheduler.work_counts.fetch_add(1, Ordering::SeqCst);
+        (Some(coro), handle)
     }
 
     /// Spawn a new coroutine
-    pub fn spawn<F>(f: F)
-        where F: FnOnce() + 'static + Send
+    pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+        where F: FnOnce() -> T + 'static + Send,
+              T: Send + 'static
     {
         Scheduler::spawn_opts(f, Default::default())
     }
 
     /// Spawn a new coroutine with options
-    pub fn spawn_opts<F>(f: F, opts: Options)
-        where F: FnOnce() + 'static + Send
+    pub fn spawn_opts<F, T>(f: F, opts: Options) -> JoinHandle<T>
+        where F: FnOnce() -> T + 'static + Send,
+              T: Send + 'static
     {
-        Scheduler::get().work_counts.fetch_add(1, Ordering::SeqCst);
-        Processor::current().spawn_opts(f, opts)
+        match Processor::try_current() {
+            Some(processor) => processor.spawn_opts(f, opts),
+            // Queued for the processors of `Scheduler::run`
+            None => {
+                let (coro, handle) = Scheduler::new_coroutine(&SCHEDULER, f, opts);
+                if let Some(coro) = coro {
+                    Scheduler::ready(coro);
+                }
+                handle
+            }
+        }
     }
 
-    /// Run the scheduler with `n` threads
+    /// Run the global scheduler with `n` threads
     pub fn run(n: usize) {
+        Scheduler::run_threads(&SCHEDULER, n);
+    }
+
+    #[doc(hidden)]
+    /// Run `scheduler` with `n` threads until all of its works are done, the current
+    /// thread is one of them
+    ///
+    /// The processors get the IDs from `0` to `n - 1`, `0` runs in the current thread.
+    pub fn run_threads(scheduler: &Arc<Scheduler>, n: usize) {
+        dump::register(scheduler);
+        scheduler.threads.store(n, Ordering::SeqCst);
+
         let mut futs = Vec::new();
-        for _ in 1..n {
-            let fut = thread::spawn(|| {
-                match Processor::current().schedule() {
-                    Ok(..) => {},
-                    Err(err) => error!("Processor schedule error: {:?}", err),
-                }
-            });
+        for id in 1..n {
+            let scheduler = scheduler.clone();
+            let fut = thread::spawn(move|| Processor::run(scheduler, id));
 
             futs.push(fut);
         }
 
-        Processor::current().schedule()
-            .unwrap_or_else(|err| {
-                error!("Processor schedule error: {:?}", err);
-            });
+        Processor::run(scheduler.clone(), 0);
 
         for fut in futs.into_iter() {
             fut.join().unwrap();
         }
+
+        // Abandon the coroutines which didn't finish in the drain period, the processors
+        // have abandoned the ones waiting for I/O and timers
+        let mut abandoned = scheduler.abandon_queued();
+
+        // The rest are blocked on a lock, a channel or a `JoinHandle`. They are finished but
+        // not freed, because they are still in their wait lists. They are freed when they
+        // are waked up or their wait lists are dropped.
+        for hdl in scheduler.coroutines.take_all() {
+            unsafe { (&mut *hdl.coro_ptr).abandon(); }
+            scheduler.work_counts.fetch_sub(1, Ordering::SeqCst);
+            abandoned += 1;
+        }
+
+        // Their joiners may have been waked up
+        abandoned += scheduler.abandon_queued();
+
+        if abandoned != 0 {
+            warn!("Scheduler has shut down with {} unfinished coroutines", abandoned);
+        }
+
+        dump::unregister(scheduler);
+    }
+
+    /// Get the information of the current coroutine
+    ///
+    /// Outside of any coroutine, it is the information of the thread's main coroutine.
+    pub fn current() -> CoroutineInfo {
+        match Processor::try_current() {
+            Some(processor) => {
+                let coro = processor.current_coroutine();
+                unsafe { (&*coro).info().clone() }
+            },
+            None => CoroutineInfo::of_thread(),
+        }
     }
 
     /// Suspend the current coroutine
     pub fn sched() {
-        Processor::current().sched();
+        if let Some(processor) = Processor::try_current() {
+            processor.sched();
+        }
     }
 
     /// Block the current coroutine
     pub fn block() {
         Processor::current().block();
     }
+
+    /// Put the current coroutine to sleep for `dur`
+    pub fn sleep(dur: Duration) {
+        match Processor::try_current() {
+            Some(processor) => processor.sleep(dur),
+            None => thread::sleep(dur),
+        }
+    }
+
+    /// Put the current coroutine to sleep until `deadline`
+    pub fn sleep_until(deadline: Instant) {
+        let now = Instant::now();
+        if deadline > now {
+            Scheduler::sleep(deadline - now);
+        }
+    }
+
+    /// Run the blocking `f` in a separate thread pool, so it won't stall the processor
+    ///
+    /// The current coroutine is blocked until `f` returns, and is woken up by
+    /// `Scheduler::ready`. Returns an error if `f` panicked.
+    pub fn spawn_blocking<F, T>(f: F) -> thread::Result<T>
+        where F: FnOnce() -> T + Send + 'static,
+              T: Send + 'static
+    {
+        Scheduler::get().blocking_pool.run(f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::usize;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    use coroutine::Coroutine;
+    use options::Options;
+    use runtime::{Runtime, Config};
+    use processor::Processor;
+    use sync::Mutex;
+
+    use super::{Scheduler, CoroutineRefMut};
+
+    #[test]
+    fn test_current() {
+        let runtime = Runtime::new(Config::new());
+
+        let hdl = runtime.spawn_opts(|| {
+            let current = Scheduler::current();
+            let child = Scheduler::spawn(|| Scheduler::current()).join().unwrap();
+
+            assert!(child.id() != current.id());
+            assert_eq!(child.name(), None);
+            (current.id(), current.name().map(|name| name.to_owned()))
+        }, Options::new().name(Some("worker".to_owned())));
+
+        runtime.run();
+
+        let (id, name) = hdl.join().unwrap();
+        assert!(id != Scheduler::current().id());
+        assert_eq!(name, Some("worker".to_owned()));
+    }
+
+    #[test]
+    fn test_outside_without_processor() {
+        let runtime = Runtime::new(Config::new());
+        let hdl = runtime.spawn(|| 1);
+        runtime.run();
+
+        // None of them needs a processor in a plain thread
+        assert_eq!(Scheduler::current().id(), Scheduler::current().id());
+        assert_eq!(hdl.join().unwrap(), 1);
+        *Mutex::new(0).lock().unwrap() += 1;
+        Scheduler::sched();
+        Scheduler::sleep(Duration::from_millis(1));
+
+        assert!(Processor::try_current().is_none());
+    }
+
+    #[test]
+    fn test_stats() {
+        let runtime = Runtime::new(Config::new());
+
+        let hdl = runtime.spawn(|| {
+            let _ = Scheduler::spawn(|| panic!("Panicked inside coroutine")).join();
+            Scheduler::sleep(Duration::from_millis(10));
+            Scheduler::get().stats()
+        });
+
+        runtime.run();
+
+        let stats = hdl.join().unwrap();
+        assert_eq!(stats.work_count, 1);
+        assert_eq!(stats.processors.len(), 1);
+
+        let processor = &stats.processors[0];
+        assert!(processor.context_switches >= 3);
+        assert_eq!(processor.coroutines_finished, 1);
+        assert_eq!(processor.panics, 1);
+        assert_eq!(stats.stack_pool.in_use, 1);
+        assert_eq!(stats.stack_pool.cached, 1);
+        assert!(processor.idle_time > Duration::from_millis(0));
+        assert_eq!(runtime.scheduler().stats().processors.len(), 0);
+    }
+
+    #[test]
+    fn test_pinned() {
+        let runtime = Runtime::new(Config::new().threads(4));
+
+        let hdls: Vec<_> = (0..8).map(|_| {
+            runtime.spawn_opts(|| {
+                let id = Processor::current().id();
+                for i in 0..20 {
+                    if i % 2 == 0 {
+                        Scheduler::sched();
+                    } else {
+                        Scheduler::sleep(Duration::from_millis(1));
+                    }
+                    assert_eq!(Processor::current().id(), id);
+                }
+            }, Options::new().pinned(true))
+        }).collect();
+
+        runtime.run();
+
+        for hdl in hdls {
+            hdl.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_steal() {
+        let runtime = Runtime::new(Config::new().threads(2));
+
+        let hdl = runtime.spawn(|| {
+            let resumed = Arc::new(AtomicUsize::new(usize::MAX));
+
+            // Runs immediately and keeps this processor busy, so this coroutine stays in the
+            // local queue until the other processor steals it
+            let busy = {
+                let resumed = resumed.clone();
+                Scheduler::spawn(move|| {
+                    let deadline = Instant::now() + Duration::from_secs(5);
+                    while resumed.load(Ordering::SeqCst) == usize::MAX && Instant::now() < deadline {}
+                    Processor::current().id()
+                })
+            };
+
+            let id = Processor::current().id();
+            resumed.store(id, Ordering::SeqCst);
+            (busy.join().unwrap(), id)
+        });
+
+        runtime.run();
+
+        let (busy, stolen) = hdl.join().unwrap();
+        assert!(busy != stolen);
+    }
+
+    #[test]
+    fn test_global_queue_overflow() {
+        let scheduler = Scheduler::new(&Config::new().global_queue_size(2));
+
+        // Never resumed, only the pointers are compared
+        let coro = |i: usize| CoroutineRefMut::new((i * 8) as *mut Coroutine);
+        let pop = |scheduler: &Scheduler| scheduler.pop_global().map(|coro| coro.coro_ptr as usize / 8);
+
+        for i in 1..5 {
+            scheduler.push_global(coro(i));
+        }
+        assert_eq!(scheduler.overflow_count(), 2);
+
+        // Goes to the overflow list even though the bounded queue has room again
+        assert_eq!(pop(&scheduler), Some(1));
+        scheduler.push_global(coro(5));
+        assert_eq!(scheduler.overflow_count(), 3);
+
+        for i in 2..6 {
+            assert_eq!(pop(&scheduler), Some(i));
+        }
+        assert_eq!(pop(&scheduler), None);
+
+        scheduler.push_global(coro(6));
+        assert_eq!(scheduler.overflow_count(), 3);
+        assert_eq!(pop(&scheduler), Some(6));
+    }
 }
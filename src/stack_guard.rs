@@ -0,0 +1,362 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Stack overflow detection for coroutine stacks
+//!
+//! Every coroutine stack is mmapped by `context::Stack` with a `PROT_NONE` guard page at
+//! its low end. A `SIGSEGV` (or `SIGBUS`) handler, running on an alternate signal stack,
+//! checks whether the fault address is in the guard page of the coroutine running in the
+//! faulting thread. If so, it reports the overflowed coroutine and aborts, otherwise the
+//! previous handler takes over.
+
+use std::cell::Cell;
+use std::ptr;
+use std::sync::{Once, ONCE_INIT};
+
+use libc;
+
+use coroutine::CoroutineInfo;
+
+/// Guard page and identity of the coroutine running in the current thread
+#[derive(Clone, Copy)]
+struct Running {
+    guard_start: usize,
+    guard_end: usize,
+    info: *const CoroutineInfo,
+}
+
+impl Running {
+    /// Whether `addr` is in the guard page
+    fn is_guard(&self, addr: usize) -> bool {
+        addr >= self.guard_start && addr < self.guard_end
+    }
+}
+
+thread_local!(static RUNNING: Cell<Running> = Cell::new(Running {
+    guard_start: 0,
+    guard_end: 0,
+    info: ptr::null(),
+}));
+
+/// Record the coroutine which is going to run in the current thread, `None` for the
+/// processor's own stack
+pub fn set_running(guard: Option<(usize, usize)>, info: *const CoroutineInfo) {
+    let (guard_start, guard_end) = guard.unwrap_or((0, 0));
+    RUNNING.with(|r| r.set(Running {
+        guard_start: guard_start,
+        guard_end: guard_end,
+        info: info,
+    }));
+}
+
+/// The guard page and coroutine recorded by `set_running` in the current thread
+pub fn running() -> (Option<(usize, usize)>, *const CoroutineInfo) {
+    let running = RUNNING.with(|r| r.get());
+    if running.guard_start == running.guard_end {
+        (None, running.info)
+    } else {
+        (Some((running.guard_start, running.guard_end)), running.info)
+    }
+}
+
+/// Install the handler once per process, and the alternate signal stack once per thread
+pub fn init() {
+    static INSTALL: Once = ONCE_INIT;
+    INSTALL.call_once(|| unsafe { imp::install() });
+
+    // Make sure the thread local is initialized outside of the handler
+    RUNNING.with(|_| {});
+    unsafe { imp::ensure_altstack() };
+}
+
+fn write_stderr(s: &[u8]) {
+    unsafe {
+        imp::write(2, s.as_ptr() as *const libc::c_void, s.len() as libc::size_t);
+    }
+}
+
+fn write_usize(mut n: usize) {
+    let mut buf = [0u8; 20];
+    let mut pos = buf.len();
+    loop {
+        pos -= 1;
+        buf[pos] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    write_stderr(&buf[pos..]);
+}
+
+/// Called by the signal handler, only async-signal-safe functions are allowed here
+fn report_if_overflowed(addr: usize) {
+    let running = RUNNING.with(|r| r.get());
+    if !running.is_guard(addr) {
+        return;
+    }
+
+    // A generator running outside of any coroutine has no identity
+    if running.info.is_null() {
+        write_stderr(b"\nGenerator");
+    } else {
+        let info = unsafe { &*running.info };
+        write_stderr(b"\nCoroutine #");
+        write_usize(info.id());
+        if let Some(name) = info.name() {
+            write_stderr(b" (");
+            write_stderr(name.as_bytes());
+            write_stderr(b")");
+        }
+    }
+    write_stderr(b" has overflowed its stack\n");
+
+    unsafe { imp::abort(); }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod imp {
+    use std::mem;
+    use std::ptr;
+
+    use libc::{self, c_int, c_void, size_t};
+
+    #[cfg(target_os = "linux")]
+    mod consts {
+        use libc::{c_int, size_t};
+
+        pub const SIGBUS: c_int = 7;
+        pub const SA_SIGINFO: c_int = 0x00000004;
+        pub const SA_ONSTACK: c_int = 0x08000000;
+        pub const SS_DISABLE: c_int = 2;
+        pub const SIGSTKSZ: size_t = 8192;
+
+        #[repr(C)]
+        pub struct sigaction {
+            pub sa_sigaction: usize,
+            pub sa_mask: [u64; 16],
+            pub sa_flags: c_int,
+            pub sa_restorer: usize,
+        }
+
+        #[repr(C)]
+        pub struct stack_t {
+            pub ss_sp: *mut ::libc::c_void,
+            pub ss_flags: c_int,
+            pub ss_size: size_t,
+        }
+
+        #[repr(C)]
+        pub struct siginfo {
+            pub si_signo: c_int,
+            pub si_errno: c_int,
+            pub si_code: c_int,
+            pub si_addr: *mut ::libc::c_void,
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    mod consts {
+        use libc::{c_int, size_t};
+
+        pub const SIGBUS: c_int = 10;
+        pub const SA_SIGINFO: c_int = 0x0040;
+        pub const SA_ONSTACK: c_int = 0x0001;
+        pub const SS_DISABLE: c_int = 0x0004;
+        pub const SIGSTKSZ: size_t = 131072;
+
+        #[repr(C)]
+        pub struct sigaction {
+            pub sa_sigaction: usize,
+            pub sa_mask: u32,
+            pub sa_flags: c_int,
+        }
+
+        #[repr(C)]
+        pub struct stack_t {
+            pub ss_sp: *mut ::libc::c_void,
+            pub ss_size: size_t,
+            pub ss_flags: c_int,
+        }
+
+        #[repr(C)]
+        pub struct siginfo {
+            pub si_signo: c_int,
+            pub si_errno: c_int,
+            pub si_code: c_int,
+            pub si_pid: c_int,
+            pub si_uid: u32,
+            pub si_status: c_int,
+            pub si_addr: *mut ::libc::c_void,
+        }
+    }
+
+    use self::consts::*;
+
+    const SIGSEGV: c_int = 11;
+    const PROT_READ: c_int = 1;
+    const PROT_WRITE: c_int = 2;
+    const MAP_PRIVATE: c_int = 0x0002;
+    #[cfg(target_os = "linux")]
+    const MAP_ANON: c_int = 0x0020;
+    #[cfg(target_os = "macos")]
+    const MAP_ANON: c_int = 0x1000;
+
+    extern {
+        fn sigaction(signum: c_int, act: *const sigaction, oldact: *mut sigaction) -> c_int;
+        fn sigaltstack(ss: *const stack_t, oss: *mut stack_t) -> c_int;
+        fn mmap(addr: *mut c_void, len: size_t, prot: c_int, flags: c_int, fd: c_int,
+                offset: libc::off_t) -> *mut c_void;
+        pub fn write(fd: c_int, buf: *const c_void, count: size_t) -> libc::ssize_t;
+        pub fn abort() -> !;
+    }
+
+    // Allocated by `install`, the handler must not allocate
+    static mut PREV_SEGV: *mut sigaction = 0 as *mut sigaction;
+    static mut PREV_BUS: *mut sigaction = 0 as *mut sigaction;
+
+    extern "C" fn handler(signum: c_int, info: *mut siginfo, _: *mut c_void) {
+        let addr = unsafe { (*info).si_addr as usize };
+        super::report_if_overflowed(addr);
+
+        // Not a coroutine stack overflow, restore the previous handler and let the
+        // faulting instruction run again
+        unsafe {
+            let prev = if signum == SIGSEGV { PREV_SEGV } else { PREV_BUS };
+            sigaction(signum, prev, ptr::null_mut());
+        }
+    }
+
+    pub unsafe fn install() {
+        let mut action: sigaction = mem::zeroed();
+        action.sa_sigaction = handler as usize;
+        action.sa_flags = SA_SIGINFO | SA_ONSTACK;
+
+        PREV_SEGV = Box::into_raw(Box::new(mem::zeroed()));
+        PREV_BUS = Box::into_raw(Box::new(mem::zeroed()));
+
+        sigaction(SIGSEGV, &action, PREV_SEGV);
+        sigaction(SIGBUS, &action, PREV_BUS);
+    }
+
+    /// The handler can't run on the overflowed stack
+    pub unsafe fn ensure_altstack() {
+        let mut current: stack_t = mem::zeroed();
+        sigaltstack(ptr::null(), &mut current);
+        if current.ss_flags & SS_DISABLE == 0 {
+            // The standard library has installed one for this thread
+            return;
+        }
+
+        let stack = mmap(ptr::null_mut(), SIGSTKSZ, PROT_READ | PROT_WRITE,
+                         MAP_PRIVATE | MAP_ANON, -1, 0);
+        if stack as isize == -1 {
+            error!("Failed to allocate the alternate signal stack");
+            return;
+        }
+
+        let mut altstack: stack_t = mem::zeroed();
+        altstack.ss_sp = stack;
+        altstack.ss_size = SIGSTKSZ;
+        altstack.ss_flags = 0;
+        // Leaked on purpose, the thread may receive a signal until it exits
+        sigaltstack(&altstack, ptr::null_mut());
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+mod imp {
+    use libc::{c_int, c_void, size_t, ssize_t};
+
+    pub unsafe fn install() {}
+    pub unsafe fn ensure_altstack() {}
+
+    extern {
+        pub fn write(fd: c_int, buf: *const c_void, count: size_t) -> ssize_t;
+        pub fn abort() -> !;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::process::Command;
+    use std::ptr;
+    use std::usize;
+
+    use options::Options;
+    use runtime::{Runtime, Config};
+
+    use super::Running;
+
+    #[test]
+    fn test_guard_range() {
+        let running = Running {
+            guard_start: 0x1000,
+            guard_end: 0x2000,
+            info: ptr::null(),
+        };
+        assert!(!running.is_guard(0xfff));
+        assert!(running.is_guard(0x1000));
+        assert!(running.is_guard(0x1fff));
+        assert!(!running.is_guard(0x2000));
+
+        // The processor's own stack
+        let none = Running {
+            guard_start: 0,
+            guard_end: 0,
+            info: ptr::null(),
+        };
+        assert!(!none.is_guard(0));
+    }
+
+    fn recurse(depth: usize) -> usize {
+        let frame = [depth; 128];
+        if depth == usize::MAX {
+            0
+        } else {
+            recurse(depth + 1) + frame[depth % 128]
+        }
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    fn test_overflow_reported() {
+        // The overflow aborts, so it happens in a child process running this test again
+        if env::var("SIMPLESCHED_OVERFLOW_CHILD").is_ok() {
+            let runtime = Runtime::new(Config::new());
+            runtime.spawn_opts(|| recurse(0),
+                               Options::new().stack_size(64 * 1024).name(Some("deep".to_owned())));
+            runtime.run();
+            return;
+        }
+
+        let output = Command::new(env::current_exe().unwrap())
+            .arg("test_overflow_reported")
+            .env("SIMPLESCHED_OVERFLOW_CHILD", "1")
+            .output()
+            .unwrap();
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(!output.status.success());
+        assert!(stderr.contains(" (deep) has overflowed its stack"), "{}", stderr);
+    }
+}
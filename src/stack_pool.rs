@@ -0,0 +1,194 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Pool of coroutine stacks shared by the processors of a scheduler
+
+use std::fmt;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use libc;
+use context::Stack;
+
+// Stacks are grouped by sizes of powers of two
+const SIZE_CLASSES: usize = 64;
+
+// The guard page takes one page, at least one more page is needed for the stack itself
+const MIN_STACK_SIZE: usize = 0x2000;
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+const MADV_DONTNEED: libc::c_int = 4;
+
+extern {
+    fn madvise(addr: *mut libc::c_void, len: libc::size_t, advice: libc::c_int) -> libc::c_int;
+}
+
+/// Statistics of a stack pool
+#[derive(Clone, Debug)]
+pub struct StackPoolStats {
+    /// Number of stacks taken by the coroutines
+    pub in_use: usize,
+    /// Number of stacks cached in the pool
+    pub cached: usize,
+    /// Number of stacks allocated
+    pub allocated: usize,
+    /// Number of stacks taken from the cache instead of being allocated
+    pub reused: usize,
+    /// Number of stacks freed because the pool was full
+    pub released: usize,
+}
+
+/// Bounded pool of stacks, grouped by size classes
+///
+/// Every scheduler creates its own pool from its `Config`, unless a pool is given with
+/// `Config::stack_pool`, which lets several runtimes share the same stacks.
+pub struct StackPool {
+    classes: Vec<Mutex<Vec<Stack>>>,
+    max_cached: usize,
+    madvise: bool,
+    in_use: AtomicUsize,
+    cached: AtomicUsize,
+    allocated: AtomicUsize,
+    reused: AtomicUsize,
+    released: AtomicUsize,
+}
+
+// The stacks are only touched by one thread at a time, either by the coroutine owning
+// it, or under the lock of their size class
+unsafe impl Send for StackPool {}
+unsafe impl Sync for StackPool {}
+
+impl fmt::Debug for StackPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "StackPool {{ max_cached: {}, madvise: {}, stats: {:?} }}",
+               self.max_cached, self.madvise, self.stats())
+    }
+}
+
+fn size_class(size: usize) -> usize {
+    let size = if size < MIN_STACK_SIZE { MIN_STACK_SIZE } else { size };
+    size.next_power_of_two().trailing_zeros() as usize
+}
+
+impl StackPool {
+    /// Cache `max_cached` stacks at most, the pages of cached stacks will be given back
+    /// to the OS with `madvise(MADV_DONTNEED)` if `madvise` is set
+    pub fn new(max_cached: usize, madvise: bool) -> StackPool {
+        StackPool {
+            classes: (0..SIZE_CLASSES).map(|_| Mutex::new(Vec::new())).collect(),
+            max_cached: max_cached,
+            madvise: madvise,
+            in_use: AtomicUsize::new(0),
+            cached: AtomicUsize::new(0),
+            allocated: AtomicUsize::new(0),
+            reused: AtomicUsize::new(0),
+            released: AtomicUsize::new(0),
+        }
+    }
+
+    /// Take a stack of at least `size` bytes, including the guard page
+    pub fn take_stack(&self, size: usize) -> Stack {
+        let class = size_class(size);
+        self.in_use.fetch_add(1, Ordering::Relaxed);
+
+        if let Some(stack) = self.classes[class].lock().unwrap().pop() {
+            self.cached.fetch_sub(1, Ordering::Relaxed);
+            self.reused.fetch_add(1, Ordering::Relaxed);
+            return stack;
+        }
+
+        self.allocated.fetch_add(1, Ordering::Relaxed);
+        Stack::new(1 << class)
+    }
+
+    /// Put a stack back into the pool, it is freed if the pool is full
+    pub fn give_stack(&self, stack: Stack) {
+        self.in_use.fetch_sub(1, Ordering::Relaxed);
+
+        // Reserve a slot first, so concurrent returns won't exceed the limit
+        if self.cached.fetch_add(1, Ordering::Relaxed) >= self.max_cached {
+            self.cached.fetch_sub(1, Ordering::Relaxed);
+            self.released.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        let size = stack.end() as usize - stack.start() as usize;
+        if self.madvise {
+            dont_need(&stack);
+        }
+
+        self.classes[size_class(size)].lock().unwrap().push(stack);
+    }
+
+    /// Take a snapshot of the statistics
+    pub fn stats(&self) -> StackPoolStats {
+        StackPoolStats {
+            in_use: self.in_use.load(Ordering::Relaxed),
+            cached: self.cached.load(Ordering::Relaxed),
+            allocated: self.allocated.load(Ordering::Relaxed),
+            reused: self.reused.load(Ordering::Relaxed),
+            released: self.released.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Give the pages above the guard page back to the OS, they will be zero filled on the
+/// next access
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+fn dont_need(stack: &Stack) {
+    let start = stack.guard() as usize;
+    let len = stack.end() as usize - start;
+    unsafe {
+        if madvise(start as *mut libc::c_void, len as libc::size_t, MADV_DONTNEED) != 0 {
+            debug!("madvise on a cached stack failed");
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+fn dont_need(_: &Stack) {}
+
+#[cfg(test)]
+mod test {
+    use super::StackPool;
+
+    #[test]
+    fn test_stack_pool() {
+        let pool = StackPool::new(1, true);
+
+        let a = pool.take_stack(0x10000);
+        let b = pool.take_stack(0x10000);
+        pool.give_stack(a);
+        pool.give_stack(b);
+
+        // Same size class
+        let c = pool.take_stack(0xf000);
+        assert_eq!(c.end() as usize - c.start() as usize, 0x10000);
+        pool.give_stack(c);
+
+        let stats = pool.stats();
+        assert_eq!(stats.in_use, 0);
+        assert_eq!(stats.cached, 1);
+        assert_eq!(stats.allocated, 2);
+        assert_eq!(stats.reused, 1);
+        assert_eq!(stats.released, 1);
+    }
+}
@@ -0,0 +1,117 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy of
+// this software and associated documentation files (the "Software"), to deal in
+// the Software without restriction, including without limitation the rights to
+// use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software is furnished to do so,
+// subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+// FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+// COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+// IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+// CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Runtime statistics
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use stack_pool::StackPoolStats;
+
+/// Snapshot of the statistics of a scheduler, returned by `Scheduler::stats`
+#[derive(Clone, Debug)]
+pub struct Stats {
+    /// Number of coroutines which are not finished
+    pub work_count: usize,
+    /// Number of coroutines waiting in the global queue
+    pub global_queue_depth: usize,
+    /// Number of times the global queue was full and coroutines went to the overflow list
+    pub overflow_count: usize,
+    /// Statistics of the stack pool used by the scheduler
+    pub stack_pool: StackPoolStats,
+    /// Statistics of the processors which are running
+    pub processors: Vec<ProcessorStats>,
+}
+
+/// Statistics of a processor, each processor is a worker thread
+#[derive(Clone, Debug)]
+pub struct ProcessorStats {
+    pub id: usize,
+    /// Number of times a coroutine was resumed
+    pub context_switches: usize,
+    /// Number of coroutines finished, including the panicked ones
+    pub coroutines_finished: usize,
+    /// Number of coroutines panicked
+    pub panics: usize,
+    /// Number of coroutines waked up by I/O events
+    pub io_wakeups: usize,
+    /// Time spent waiting for works
+    pub idle_time: Duration,
+    /// Number of coroutines in the local queue
+    pub queue_depth: usize,
+    /// Number of coroutines waiting for I/O events
+    pub io_waiters: usize,
+    /// Number of sleeping coroutines
+    pub sleepers: usize,
+}
+
+#[doc(hidden)]
+/// Counters updated by a processor, and read by the other threads
+pub struct Counters {
+    pub context_switches: AtomicUsize,
+    pub coroutines_finished: AtomicUsize,
+    pub panics: AtomicUsize,
+    pub io_wakeups: AtomicUsize,
+    pub idle_us: AtomicUsize,
+    pub queue_depth: AtomicUsize,
+    pub io_waiters: AtomicUsize,
+    pub sleepers: AtomicUsize,
+}
+
+impl Counters {
+    pub fn new() -> Counters {
+        Counters {
+            context_switches: AtomicUsize::new(0),
+            coroutines_finished: AtomicUsize::new(0),
+            panics: AtomicUsize::new(0),
+            io_wakeups: AtomicUsize::new(0),
+            idle_us: AtomicUsize::new(0),
+            queue_depth: AtomicUsize::new(0),
+            io_waiters: AtomicUsize::new(0),
+            sleepers: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn incr(counter: &AtomicUsize) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_idle(&self, dur: Duration) {
+        let us = dur.as_secs() as usize * 1_000_000 + dur.subsec_nanos() as usize / 1_000;
+        self.idle_us.fetch_add(us, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self, id: usize) -> ProcessorStats {
+        let idle_us = self.idle_us.load(Ordering::Relaxed) as u64;
+
+        ProcessorStats {
+            id: id,
+            context_switches: self.context_switches.load(Ordering::Relaxed),
+            coroutines_finished: self.coroutines_finished.load(Ordering::Relaxed),
+            panics: self.panics.load(Ordering::Relaxed),
+            io_wakeups: self.io_wakeups.load(Ordering::Relaxed),
+            idle_time: Duration::new(idle_us / 1_000_000, (idle_us % 1_000_000) as u32 * 1_000),
+            queue_depth: self.queue_depth.load(Ordering::Relaxed),
+            io_waiters: self.io_waiters.load(Ordering::Relaxed),
+            sleepers: self.sleepers.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -23,5 +23,8 @@
 //! Coroutine synchronization
 
 pub use self::mutex::Mutex;
+pub use self::mpsc::{channel, sync_channel, Sender, SyncSender, Receiver};
 
 pub mod mutex;
+pub mod mpsc;
+mod waiter;
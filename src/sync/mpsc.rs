@@ -0,0 +1,459 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+//  Permission is hereby granted, free of charge, to any person obtaining a
+//  copy of this software and associated documentation files (the "Software"),
+//  to deal in the Software without restriction, including without limitation
+//  the rights to use, copy, modify, merge, publish, distribute, sublicense,
+//  and/or sell copies of the Software, and to permit persons to whom the
+//  Software is furnished to do so, subject to the following conditions:
+//
+//  The above copyright notice and this permission notice shall be included in
+//  all copies or substantial portions of the Software.
+//
+//  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+//  OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+//  FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+//  DEALINGS IN THE SOFTWARE.
+
+//! Multi-producer, single-consumer FIFO queue communication primitives
+//!
+//! `recv` blocks the current coroutine only, and `send` may be called from any coroutine
+//! or any thread outside of the scheduler.
+
+pub use std::sync::mpsc::{SendError, RecvError, TryRecvError, TrySendError};
+
+use std::cell::Cell;
+use std::cmp;
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+
+use sync::waiter::{self, Waiter};
+
+struct State<T> {
+    queue: VecDeque<T>,
+    bound: Option<usize>,
+    receiver: Option<Waiter>,
+    blocked_senders: VecDeque<Waiter>,
+    senders: usize,
+    receiver_alive: bool,
+    // Number of the values pushed and popped, a rendezvous sender waits for its value
+    // to be popped
+    sent: usize,
+    taken: usize,
+    delivering: Option<Waiter>,
+}
+
+impl<T> State<T> {
+    fn is_full(&self) -> bool {
+        match self.bound {
+            // A rendezvous channel holds the value being handed over
+            Some(bound) => self.queue.len() >= cmp::max(bound, 1),
+            None => false,
+        }
+    }
+}
+
+struct Inner<T> {
+    state: Mutex<State<T>>,
+    rendezvous: bool,
+}
+
+impl<T> Inner<T> {
+    fn new(bound: Option<usize>) -> Inner<T> {
+        Inner {
+            state: Mutex::new(State {
+                queue: VecDeque::new(),
+                bound: bound,
+                receiver: None,
+                blocked_senders: VecDeque::new(),
+                senders: 1,
+                receiver_alive: true,
+                sent: 0,
+                taken: 0,
+                delivering: None,
+            }),
+            rendezvous: bound == Some(0),
+        }
+    }
+
+    /// Push a value, `need_receiver` only accepts it if the receiver is waiting
+    ///
+    /// Returns the sequence number of the value.
+    fn try_push(&self, t: T, need_receiver: bool) -> Result<usize, TrySendError<T>> {
+        let (seq, receiver) = {
+            let mut state = self.state.lock().unwrap();
+            if !state.receiver_alive {
+                return Err(TrySendError::Disconnected(t));
+            }
+
+            if state.is_full() || (need_receiver && state.receiver.is_none()) {
+                return Err(TrySendError::Full(t));
+            }
+
+            state.queue.push_back(t);
+            state.sent += 1;
+            (state.sent, state.receiver.take())
+        };
+
+        if let Some(receiver) = receiver {
+            receiver.notify();
+        }
+
+        Ok(seq)
+    }
+
+    fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
+        // Without a buffer, only a waiting receiver could take it immediately
+        self.try_push(t, self.rendezvous).map(|_| ())
+    }
+
+    fn send(&self, mut t: T) -> Result<(), SendError<T>> {
+        loop {
+            match self.try_push(t, false) {
+                Ok(seq) if self.rendezvous => return self.wait_taken(seq),
+                Ok(..) => return Ok(()),
+                Err(TrySendError::Disconnected(v)) => return Err(SendError(v)),
+                Err(TrySendError::Full(v)) => t = v,
+            }
+
+            waiter::wait(|waiter| {
+                let mut state = self.state.lock().unwrap();
+                if state.is_full() && state.receiver_alive {
+                    state.blocked_senders.push_back(waiter);
+                } else {
+                    drop(state);
+                    waiter.notify();
+                }
+            });
+        }
+    }
+
+    /// Wait until the value `seq` has been taken by the receiver
+    fn wait_taken(&self, seq: usize) -> Result<(), SendError<T>> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.taken >= seq {
+                    return Ok(());
+                }
+
+                if !state.receiver_alive {
+                    // It is the only value in the queue
+                    return Err(SendError(state.queue.pop_back().unwrap()));
+                }
+            }
+
+            waiter::wait(|waiter| {
+                let mut state = self.state.lock().unwrap();
+                if state.taken < seq && state.receiver_alive {
+                    state.delivering = Some(waiter);
+                } else {
+                    drop(state);
+                    waiter.notify();
+                }
+            });
+        }
+    }
+
+    fn try_recv(&self) -> Result<T, TryRecvError> {
+        let (t, sender, delivering) = {
+            let mut state = self.state.lock().unwrap();
+            match state.queue.pop_front() {
+                Some(t) => {
+                    state.taken += 1;
+                    (t, state.blocked_senders.pop_front(), state.delivering.take())
+                },
+                None if state.senders == 0 => return Err(TryRecvError::Disconnected),
+                None => return Err(TryRecvError::Empty),
+            }
+        };
+
+        if let Some(sender) = delivering {
+            sender.notify();
+        }
+        if let Some(sender) = sender {
+            sender.notify();
+        }
+
+        Ok(t)
+    }
+
+    fn recv(&self) -> Result<T, RecvError> {
+        loop {
+            match self.try_recv() {
+                Ok(t) => return Ok(t),
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+                Err(TryRecvError::Empty) => {},
+            }
+
+            waiter::wait_abortable(|waiter| {
+                let mut state = self.state.lock().unwrap();
+                if state.queue.is_empty() && state.senders != 0 {
+                    state.receiver = Some(waiter);
+                } else {
+                    drop(state);
+                    waiter.notify();
+                }
+            }, |coro| {
+                let mut state = self.state.lock().unwrap();
+                let waiting = state.receiver.as_ref().map_or(false, |w| w.is_coroutine(coro));
+                if waiting {
+                    state.receiver.take()
+                } else {
+                    None
+                }
+            });
+        }
+    }
+
+    fn add_sender(&self) {
+        self.state.lock().unwrap().senders += 1;
+    }
+
+    fn drop_sender(&self) {
+        let receiver = {
+            let mut state = self.state.lock().unwrap();
+            state.senders -= 1;
+            if state.senders == 0 {
+                state.receiver.take()
+            } else {
+                None
+            }
+        };
+
+        if let Some(receiver) = receiver {
+            receiver.notify();
+        }
+    }
+
+    fn drop_receiver(&self) {
+        let senders = {
+            let mut state = self.state.lock().unwrap();
+            state.receiver_alive = false;
+            let delivering = state.delivering.take();
+            state.blocked_senders.drain(..).chain(delivering).collect::<Vec<Waiter>>()
+        };
+
+        for sender in senders.into_iter() {
+            sender.notify();
+        }
+    }
+}
+
+/// Creates a new asynchronous channel, sending never blocks
+pub fn channel<T: Send>() -> (Sender<T>, Receiver<T>) {
+    let inner = Arc::new(Inner::new(None));
+    (Sender { inner: inner.clone() }, Receiver::new(inner))
+}
+
+/// Creates a new synchronous channel with a buffer of `bound` messages, sending blocks
+/// while the buffer is full
+///
+/// A `bound` of 0 makes a rendezvous channel, `send` blocks until the receiver has taken
+/// the value, and `try_send` only succeeds if the receiver is waiting in `recv`.
+pub fn sync_channel<T: Send>(bound: usize) -> (SyncSender<T>, Receiver<T>) {
+    let inner = Arc::new(Inner::new(Some(bound)));
+    (SyncSender { inner: inner.clone() }, Receiver::new(inner))
+}
+
+/// The sending-half of an asynchronous channel
+pub struct Sender<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T: Send> Sender<T> {
+    /// Sends a value, fails if the receiver has been dropped
+    pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+        self.inner.try_send(t).map_err(|err| match err {
+            TrySendError::Disconnected(t) | TrySendError::Full(t) => SendError(t),
+        })
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.inner.add_sender();
+        Sender { inner: self.inner.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.inner.drop_sender();
+    }
+}
+
+/// The sending-half of a synchronous channel
+pub struct SyncSender<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T: Send> SyncSender<T> {
+    /// Sends a value, blocks the current coroutine while the buffer is full, or until the
+    /// value is received on a rendezvous channel
+    pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+        self.inner.send(t)
+    }
+
+    /// Attempts to send a value without blocking
+    pub fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
+        self.inner.try_send(t)
+    }
+}
+
+impl<T> Clone for SyncSender<T> {
+    fn clone(&self) -> SyncSender<T> {
+        self.inner.add_sender();
+        SyncSender { inner: self.inner.clone() }
+    }
+}
+
+impl<T> Drop for SyncSender<T> {
+    fn drop(&mut self) {
+        self.inner.drop_sender();
+    }
+}
+
+/// The receiving-half of a channel
+pub struct Receiver<T> {
+    inner: Arc<Inner<T>>,
+    // Only one coroutine could wait on the receiver
+    _marker: PhantomData<Cell<()>>,
+}
+
+impl<T: Send> Receiver<T> {
+    fn new(inner: Arc<Inner<T>>) -> Receiver<T> {
+        Receiver {
+            inner: inner,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Attempts to receive a value without blocking
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        self.inner.try_recv()
+    }
+
+    /// Receives a value, blocks the current coroutine until one is available or all the
+    /// senders have been dropped
+    ///
+    /// The coroutine is unwound if it is aborted while waiting.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        self.inner.recv()
+    }
+
+    /// Returns an iterator which blocks waiting for values until all senders are dropped
+    pub fn iter<'a>(&'a self) -> Iter<'a, T> {
+        Iter { rx: self }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.inner.drop_receiver();
+    }
+}
+
+pub struct Iter<'a, T: 'a> {
+    rx: &'a Receiver<T>,
+}
+
+impl<'a, T: Send> Iterator for Iter<'a, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.rx.recv().ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    use runtime::{Runtime, Config};
+    use scheduler::Scheduler;
+
+    use super::{channel, sync_channel, TrySendError};
+
+    #[test]
+    fn test_channel() {
+        let runtime = Runtime::new(Config::new().threads(2));
+
+        let (tx, rx) = channel();
+
+        for i in 0..10 {
+            let tx = tx.clone();
+            runtime.spawn(move|| {
+                tx.send(i).unwrap();
+            });
+        }
+
+        // Sending from a thread outside of the scheduler
+        let thread_tx = tx.clone();
+        let thread = thread::spawn(move|| {
+            thread_tx.send(10).unwrap();
+        });
+        drop(tx);
+
+        let hdl = runtime.spawn(move|| rx.iter().fold(0, |a, b| a + b));
+
+        runtime.run();
+        thread.join().unwrap();
+
+        assert_eq!(hdl.join().unwrap(), 55);
+    }
+
+    #[test]
+    fn test_sync_channel() {
+        let runtime = Runtime::new(Config::new().threads(2));
+
+        let (tx, rx) = sync_channel(1);
+
+        runtime.spawn(move|| {
+            for i in 0..100 {
+                tx.send(i).unwrap();
+            }
+        });
+
+        let hdl = runtime.spawn(move|| rx.iter().collect::<Vec<i32>>());
+
+        runtime.run();
+
+        assert_eq!(hdl.join().unwrap(), (0..100).collect::<Vec<i32>>());
+    }
+
+    #[test]
+    fn test_rendezvous() {
+        let runtime = Runtime::new(Config::new());
+
+        let (tx, rx) = sync_channel(0);
+        assert_eq!(tx.try_send(1), Err(TrySendError::Full(1)));
+
+        let sent = Arc::new(AtomicBool::new(false));
+        let sent_cloned = sent.clone();
+        runtime.spawn(move|| {
+            tx.send(2).unwrap();
+            sent_cloned.store(true, Ordering::SeqCst);
+        });
+
+        let hdl = runtime.spawn(move|| {
+            Scheduler::sleep(Duration::from_millis(20));
+            // Nobody has received it yet
+            assert!(!sent.load(Ordering::SeqCst));
+            rx.recv().unwrap()
+        });
+
+        runtime.run();
+
+        assert_eq!(hdl.join().unwrap(), 2);
+    }
+}
@@ -21,62 +21,123 @@
 //  DEALINGS IN THE SOFTWARE.
 
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex as StdMutex;
 use std::cell::UnsafeCell;
+use std::collections::VecDeque;
 use std::fmt;
 use std::error::Error;
 use std::marker::Reflect;
 use std::ops::{Deref, DerefMut};
+use std::thread;
 
-use scheduler::Scheduler;
+use sync::waiter::{self, Waiter};
 
 pub type LockResult<G> = Result<G, PoisonError<G>>;
-pub type TryLockResult<G> = Result<G, PoisonError<G>>;
+pub type TryLockResult<G> = Result<G, TryLockError<G>>;
 
-const POISON_RETRY_COUNT: usize = 102400;
+struct State {
+    locked: bool,
+    waiters: VecDeque<Waiter>,
+}
 
 /// A mutual exclusion primitive useful for protecting shared data
+///
+/// Coroutines waiting for the lock are blocked in a FIFO queue, and the lock is handed
+/// over to the first of them when it is released.
 pub struct Mutex<T> {
+    state: StdMutex<State>,
+    poisoned: AtomicBool,
     data: UnsafeCell<T>,
-    lock: AtomicBool,
 }
 
 impl<T> Mutex<T> {
     /// Creates a new mutex in an unlocked state ready for use.
     pub fn new(data: T) -> Mutex<T> {
         Mutex {
+            state: StdMutex::new(State {
+                locked: false,
+                waiters: VecDeque::new(),
+            }),
+            poisoned: AtomicBool::new(false),
             data: UnsafeCell::new(data),
-            lock: AtomicBool::new(false),
         }
     }
 
-    /// Acquires a mutex, blocking the current thread until it is able to do so.
-    pub fn lock<'a>(&'a self) -> LockResult<Guard<'a, T>> {
-        for _ in 0..POISON_RETRY_COUNT {
-            if self.lock.compare_and_swap(false, true, Ordering::SeqCst) == false {
-                return Ok(Guard::new(unsafe { &mut *self.data.get() }, self));
-            }
+    fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.locked {
+            false
+        } else {
+            state.locked = true;
+            true
+        }
+    }
 
-            Scheduler::sched();
+    /// Acquires a mutex, blocking the current coroutine until it is able to do so.
+    pub fn lock<'a>(&'a self) -> LockResult<Guard<'a, T>> {
+        if !self.try_acquire() {
+            waiter::wait(|waiter| {
+                let mut state = self.state.lock().unwrap();
+                if state.locked {
+                    state.waiters.push_back(waiter);
+                } else {
+                    state.locked = true;
+                    drop(state);
+                    waiter.notify();
+                }
+            });
         }
 
-        Err(PoisonError::new(Guard::new(unsafe { &mut *self.data.get() }, self)))
+        // The lock has been handed over to us when we are waked up
+        self.guard()
     }
 
+    /// Attempts to acquire the lock, fails with `WouldBlock` if it is held by others.
     pub fn try_lock<'a>(&'a self) -> TryLockResult<Guard<'a, T>> {
-        if !self.lock.compare_and_swap(false, true, Ordering::SeqCst) {
-            Ok(Guard::new(unsafe { &mut *self.data.get() }, self))
+        if self.try_acquire() {
+            self.guard().map_err(TryLockError::Poisoned)
+        } else {
+            Err(TryLockError::WouldBlock)
+        }
+    }
+
+    /// Whether a holder of this mutex panicked
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::SeqCst)
+    }
+
+    fn guard<'a>(&'a self) -> LockResult<Guard<'a, T>> {
+        let guard = Guard::new(unsafe { &mut *self.data.get() }, self);
+        if self.is_poisoned() {
+            Err(PoisonError::new(guard))
         } else {
-            Err(PoisonError::new(Guard::new(unsafe { &mut *self.data.get() }, self)))
+            Ok(guard)
+        }
+    }
+
+    fn unlock(&self) {
+        let waiter = {
+            let mut state = self.state.lock().unwrap();
+            let waiter = state.waiters.pop_front();
+            if waiter.is_none() {
+                state.locked = false;
+            }
+            waiter
+        };
+
+        if let Some(waiter) = waiter {
+            waiter.notify();
         }
     }
 }
 
 unsafe impl<T: Send> Send for Mutex<T> {}
-unsafe impl<T: Sync> Sync for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
 
 pub struct Guard<'a, T: 'a> {
     data: &'a mut T,
     mutex: &'a Mutex<T>,
+    panicking: bool,
 }
 
 impl<'a, T: 'a> Guard<'a, T> {
@@ -84,13 +145,18 @@ impl<'a, T: 'a> Guard<'a, T> {
         Guard {
             data: data,
             mutex: mutex,
+            panicking: thread::panicking(),
         }
     }
 }
 
 impl<'a, T: 'a> Drop for Guard<'a, T> {
     fn drop(&mut self) {
-        while self.mutex.lock.compare_and_swap(true, false, Ordering::SeqCst) == true {}
+        if !self.panicking && thread::panicking() {
+            self.mutex.poisoned.store(true, Ordering::SeqCst);
+        }
+
+        self.mutex.unlock();
     }
 }
 
@@ -149,20 +215,64 @@ impl<T: Send + Reflect> Error for PoisonError<T> {
     }
 }
 
+/// An enumeration of possible errors of `try_lock`
+pub enum TryLockError<T> {
+    /// The lock could not be acquired because another holder panicked
+    Poisoned(PoisonError<T>),
+    /// The lock could not be acquired at this time because it is held by others
+    WouldBlock,
+}
+
+impl<T> From<PoisonError<T>> for TryLockError<T> {
+    fn from(err: PoisonError<T>) -> TryLockError<T> {
+        TryLockError::Poisoned(err)
+    }
+}
+
+impl<T> fmt::Debug for TryLockError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TryLockError::Poisoned(..) => "Poisoned(..)".fmt(f),
+            TryLockError::WouldBlock => "WouldBlock".fmt(f),
+        }
+    }
+}
+
+impl<T> fmt::Display for TryLockError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TryLockError::Poisoned(ref p) => p.fmt(f),
+            TryLockError::WouldBlock => "try_lock failed because the operation would block".fmt(f),
+        }
+    }
+}
+
+impl<T: Send + Reflect> Error for TryLockError<T> {
+    fn description(&self) -> &str {
+        match *self {
+            TryLockError::Poisoned(ref p) => p.description(),
+            TryLockError::WouldBlock => "try_lock failed because the operation would block",
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::sync::Arc;
 
     use scheduler::Scheduler;
+    use runtime::{Runtime, Config};
 
-    use super::Mutex;
+    use super::{Mutex, TryLockError};
 
     #[test]
     fn test_mutex() {
+        let runtime = Runtime::new(Config::new().threads(10));
+
         let num = Arc::new(Mutex::new(0));
 
         let cloned_num = num.clone();
-        Scheduler::spawn(move|| {
+        runtime.spawn(move|| {
             for _ in 0..100 {
                 let num = cloned_num.clone();
                 Scheduler::spawn(move|| {
@@ -175,8 +285,40 @@ mod test {
             }
         });
 
-        Scheduler::run(10);
+        runtime.run();
 
         assert_eq!(*num.lock().unwrap(), 1000);
     }
+
+    #[test]
+    fn test_mutex_poison() {
+        let runtime = Runtime::new(Config::new());
+
+        let num = Arc::new(Mutex::new(0));
+
+        let cloned_num = num.clone();
+        let hdl = runtime.spawn(move|| {
+            let guard = cloned_num.lock().unwrap();
+
+            let num = cloned_num.clone();
+            let would_block = match num.try_lock() {
+                Err(TryLockError::WouldBlock) => true,
+                _ => false,
+            };
+            drop(guard);
+
+            let _ = Scheduler::spawn(move|| {
+                let _guard = num.lock().unwrap();
+                panic!("Panicked while holding the lock");
+            }).join();
+
+            would_block
+        });
+
+        runtime.run();
+
+        assert!(hdl.join().unwrap());
+        assert!(num.is_poisoned());
+        assert!(num.lock().is_err());
+    }
 }
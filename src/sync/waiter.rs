@@ -0,0 +1,156 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+//  Permission is hereby granted, free of charge, to any person obtaining a
+//  copy of this software and associated documentation files (the "Software"),
+//  to deal in the Software without restriction, including without limitation
+//  the rights to use, copy, modify, merge, publish, distribute, sublicense,
+//  and/or sell copies of the Software, and to permit persons to whom the
+//  Software is furnished to do so, subject to the following conditions:
+//
+//  The above copyright notice and this permission notice shall be included in
+//  all copies or substantial portions of the Software.
+//
+//  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+//  OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+//  FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+//  DEALINGS IN THE SOFTWARE.
+
+//! Coroutines or threads blocked on a synchronization primitive
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, Thread};
+
+use processor::Processor;
+use scheduler::{Scheduler, CoroutineRefMut};
+
+pub struct ThreadWaiter {
+    thread: Thread,
+    notified: AtomicBool,
+}
+
+/// A blocked coroutine, it is freed if it is dropped without being notified after its
+/// scheduler has abandoned it
+pub struct CoroutineWaiter(Option<CoroutineRefMut>);
+
+impl Drop for CoroutineWaiter {
+    fn drop(&mut self) {
+        if let Some(coro) = self.0.take() {
+            if unsafe { (&*coro.coro_ptr).is_abandoned() } {
+                Scheduler::free_abandoned(coro);
+            }
+        }
+    }
+}
+
+/// A blocked coroutine, or a blocked thread if it was not called inside a coroutine
+pub enum Waiter {
+    Coroutine(CoroutineWaiter),
+    Thread(Arc<ThreadWaiter>),
+}
+
+impl Waiter {
+    fn coroutine(coro: CoroutineRefMut) -> Waiter {
+        Waiter::Coroutine(CoroutineWaiter(Some(coro)))
+    }
+
+    /// Whether this is the waiter of the coroutine
+    pub fn is_coroutine(&self, coro: CoroutineRefMut) -> bool {
+        match *self {
+            Waiter::Coroutine(ref c) => c.0.map_or(false, |c| c.coro_ptr == coro.coro_ptr),
+            Waiter::Thread(..) => false,
+        }
+    }
+
+    /// Wake up the waiter, must be called exactly once
+    pub fn notify(self) {
+        match self {
+            Waiter::Coroutine(mut coro) => {
+                if let Some(coro) = coro.0.take() {
+                    Scheduler::ready(coro);
+                }
+            },
+            Waiter::Thread(waiter) => {
+                waiter.notified.store(true, Ordering::SeqCst);
+                waiter.thread.unpark();
+            }
+        }
+    }
+}
+
+/// Block until being notified
+///
+/// `f` is called with the `Waiter` after the current coroutine has been switched out, it
+/// should either put the `Waiter` into a wait list or notify it directly.
+pub fn wait<F>(f: F)
+    where F: FnOnce(Waiter)
+{
+    match Processor::try_current() {
+        Some(processor) if processor.running().is_some() => {
+            processor.block_with(|coro| f(Waiter::coroutine(coro)));
+        },
+        _ => {
+            let waiter = Arc::new(ThreadWaiter {
+                thread: thread::current(),
+                notified: AtomicBool::new(false),
+            });
+
+            f(Waiter::Thread(waiter.clone()));
+
+            while !waiter.notified.load(Ordering::SeqCst) {
+                thread::park();
+            }
+        }
+    }
+}
+
+/// Like `wait`, but the current coroutine is unwound if it is aborted before or while waiting
+///
+/// `remove` is called to take the `Waiter` of the coroutine out of the wait list when it is
+/// aborted, it returns `None` if the `Waiter` is not in the list.
+pub fn wait_abortable<F, R>(f: F, remove: R)
+    where F: FnOnce(Waiter),
+          R: Fn(CoroutineRefMut) -> Option<Waiter>
+{
+    let processor = match Processor::try_current() {
+        Some(processor) => processor,
+        None => return wait(f),
+    };
+
+    let coro = match processor.running() {
+        Some(coro) => coro,
+        None => return wait(f),
+    };
+
+    let cancel = match unsafe { (&*coro.coro_ptr).cancel() } {
+        Some(cancel) => cancel,
+        None => return wait(f),
+    };
+
+    processor.check_aborted();
+
+    let waker = || {
+        if let Some(waiter) = remove(coro) {
+            waiter.notify();
+        }
+    };
+
+    cancel.with_waker(&waker, || {
+        wait(|waiter| {
+            f(waiter);
+
+            // The waker may have been called before the waiter was put into the list
+            if cancel.is_cancelled() {
+                waker();
+            }
+        })
+    });
+
+    // May be resumed by another processor
+    Processor::current().check_aborted();
+}